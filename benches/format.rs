@@ -0,0 +1,104 @@
+// Benchmarks of the token hot path: filling lines with words, nested boxes
+// breaking on most lines, and pretty-printing values.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pp::{pretty_to_string, BufPrinter};
+
+const WORDS: usize = 10_000;
+
+fn fill_words(c: &mut Criterion) {
+    let words: Vec<String> = (0..WORDS).map(|i| format!("word{}", i % 100)).collect();
+    let mut group = c.benchmark_group("fill");
+    group.throughput(Throughput::Elements(WORDS as u64));
+    group.bench_function("print_string", |b| {
+        b.iter(|| {
+            let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+            f.open_hovbox(2);
+            for word in &words {
+                f.print_string(word);
+                f.print_space();
+            }
+            f.close_box();
+            black_box(f.flush_str())
+        })
+    });
+    group.bench_function("print_str", |b| {
+        b.iter(|| {
+            let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+            f.open_hovbox(2);
+            for _ in 0..WORDS {
+                f.print_str("word");
+                f.print_space();
+            }
+            f.close_box();
+            black_box(f.flush_str())
+        })
+    });
+    group.finish();
+}
+
+fn nested_boxes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nested");
+    group.throughput(Throughput::Elements(WORDS as u64));
+    group.bench_function("hvbox", |b| {
+        b.iter(|| {
+            let mut f = BufPrinter::new(40, 10, 30, i32::MAX);
+            f.open_vbox(0);
+            for i in 0..WORDS {
+                f.open_hvbox(2);
+                f.print_str("let");
+                f.print_space();
+                f.print_str(format!("x{}", i));
+                f.print_str(" =");
+                f.print_break(1, 2);
+                f.open_box(1);
+                f.print_str("f(");
+                f.print_cut();
+                f.print_str("a,");
+                f.print_space();
+                f.print_str("b)");
+                f.close_box();
+                f.close_box();
+                f.print_cut();
+            }
+            f.close_box();
+            black_box(f.flush_str())
+        })
+    });
+    group.bench_function("custom_break", |b| {
+        b.iter(|| {
+            let mut f = BufPrinter::new(40, 10, 30, i32::MAX);
+            f.open_hovbox(0);
+            for _ in 0..WORDS {
+                f.print_str("item");
+                f.print_custom_break(
+                    (",".to_string(), 1, "".to_string()),
+                    (";".to_string(), 0, "".to_string()),
+                );
+            }
+            f.close_box();
+            black_box(f.flush_str())
+        })
+    });
+    group.finish();
+}
+
+fn pretty_values(c: &mut Criterion) {
+    let value: Vec<Option<(usize, String)>> = (0..WORDS / 10)
+        .map(|i| {
+            if i % 3 == 0 {
+                None
+            } else {
+                Some((i, format!("value {}", i)))
+            }
+        })
+        .collect();
+    c.bench_function("pretty_to_string", |b| {
+        b.iter(|| black_box(pretty_to_string(black_box(&value), 78)))
+    });
+}
+
+criterion_group!(benches, fill_words, nested_boxes, pretty_values);
+criterion_main!(benches);
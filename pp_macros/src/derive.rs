@@ -0,0 +1,195 @@
+// Derivation of `Pretty` for structs and enums.
+//
+// Values print as `{:?}` shows them when they fit on the line, and as
+// `{:#?}` lays them out otherwise: one field per line, indented by 2, with a
+// trailing comma. The generated code uses the `pp_struct` and `pp_tuple`
+// builders of `BufPrinter`.
+//
+// Field attributes:
+// - `#[pretty(skip)]` leaves the field out;
+// - `#[pretty(rename = "name")]` prints the field under another name;
+// - `#[pretty(with = "path")]` prints the field with a printer function
+//   `fn(&mut BufPrinter, &T)`, as the printers taken by `%a`.
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_quote, spanned::Spanned, Data, DeriveInput, Expr, Fields, GenericParam, LitStr, Path,
+};
+
+#[derive(Default)]
+struct FieldAttrs {
+    skip: bool,
+    rename: Option<String>,
+    with: Option<Path>,
+}
+
+fn field_attrs(attrs: &[syn::Attribute]) -> syn::Result<FieldAttrs> {
+    let mut field = FieldAttrs::default();
+    for attr in attrs {
+        if !attr.path().is_ident("pretty") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                field.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                let name: LitStr = meta.value()?.parse()?;
+                field.rename = Some(name.value());
+                Ok(())
+            } else if meta.path.is_ident("with") {
+                let path: LitStr = meta.value()?.parse()?;
+                field.with = Some(path.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip`, `rename = \"...\"` or `with = \"...\"`"))
+            }
+        })?;
+    }
+    Ok(field)
+}
+
+// A field to print: its label for named fields, and an expression giving a
+// reference to its value.
+struct Printed {
+    label: Option<String>,
+    value: Expr,
+    with: Option<Path>,
+}
+
+// Bind the fields of a struct or variant. Returns the pattern of the fields
+// and the fields to print.
+fn bind_fields(fields: &Fields) -> syn::Result<(TokenStream, Vec<Printed>)> {
+    let mut printed = Vec::new();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let mut binds = Vec::new();
+            for field in &named.named {
+                let attrs = field_attrs(&field.attrs)?;
+                let ident = field.ident.as_ref().unwrap();
+                if attrs.skip {
+                    continue;
+                }
+                let var = format_ident!("__field_{}", ident);
+                binds.push(quote! { #ident: #var });
+                let label = attrs.rename.unwrap_or_else(|| {
+                    let name = ident.to_string();
+                    name.strip_prefix("r#").map(str::to_string).unwrap_or(name)
+                });
+                printed.push(Printed {
+                    label: Some(label),
+                    value: parse_quote!(#var),
+                    with: attrs.with,
+                });
+            }
+            quote! { { #(#binds,)* .. } }
+        }
+        Fields::Unnamed(unnamed) => {
+            let mut binds = Vec::new();
+            for (i, field) in unnamed.unnamed.iter().enumerate() {
+                let attrs = field_attrs(&field.attrs)?;
+                if attrs.skip {
+                    binds.push(quote! { _ });
+                    continue;
+                }
+                if attrs.rename.is_some() {
+                    return Err(syn::Error::new(
+                        field.span(),
+                        "`rename` only applies to named fields",
+                    ));
+                }
+                let var = format_ident!("__field_{}", i);
+                binds.push(quote! { #var });
+                printed.push(Printed {
+                    label: None,
+                    value: parse_quote!(#var),
+                    with: attrs.with,
+                });
+            }
+            quote! { ( #(#binds),* ) }
+        }
+        Fields::Unit => quote! {},
+    };
+    Ok((pattern, printed))
+}
+
+// The statements printing a struct or variant named `name`, with the
+// `pp_struct` or `pp_tuple` builder.
+fn print_fields(name: &str, fields: &Fields, printed: &[Printed]) -> TokenStream {
+    let calls = printed.iter().map(|field| {
+        let value = &field.value;
+        let print_value = match &field.with {
+            None => quote! { ::pp::Pretty::prettify(#value, __ppf) },
+            Some(with) => quote! { (#with)(__ppf, #value) },
+        };
+        match &field.label {
+            None => quote! { .field_with(|__ppf| #print_value) },
+            Some(label) => quote! { .field_with(#label, |__ppf| #print_value) },
+        }
+    });
+    let builder = match fields {
+        Fields::Named(_) => quote! { pp_struct },
+        _ => quote! { pp_tuple },
+    };
+    quote! {
+        __ppf.#builder(#name) #(#calls)* .finish();
+    }
+}
+
+pub fn expand(input: TokenStream) -> syn::Result<TokenStream> {
+    let mut input: DeriveInput = syn::parse2(input)?;
+
+    for param in &mut input.generics.params {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(::pp::Pretty));
+        }
+    }
+
+    let name = &input.ident;
+    let arms = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, printed) = bind_fields(&data.fields)?;
+            let body = print_fields(&input.ident.to_string(), &data.fields, &printed);
+            quote! { Self #pattern => { #body } }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let (pattern, printed) = bind_fields(&variant.fields)?;
+                let body = print_fields(&ident.to_string(), &variant.fields, &printed);
+                arms.push(quote! { Self::#ident #pattern => { #body } });
+            }
+            if arms.is_empty() {
+                // An enum without variants has no values to print.
+                let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+                return Ok(quote! {
+                    impl #impl_generics ::pp::Pretty for #name #ty_generics #where_clause {
+                        fn prettify(&self, _: &mut ::pp::BufPrinter<'_>) {
+                            match *self {}
+                        }
+                    }
+                });
+            }
+            quote! { #(#arms)* }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "`Pretty` cannot be derived for unions",
+            ))
+        }
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::pp::Pretty for #name #ty_generics #where_clause {
+            fn prettify(&self, __ppf: &mut ::pp::BufPrinter<'_>) {
+                match self {
+                    #arms
+                }
+            }
+        }
+    })
+}
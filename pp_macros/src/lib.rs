@@ -0,0 +1,35 @@
+// Procedural macros for the pp pretty-printer.
+//
+// The generated code refers to the runtime crate as `::pp`.
+
+use proc_macro::TokenStream;
+
+mod derive;
+mod printf;
+
+/*
+* Print to a `BufPrinter` with an OCaml `Format` style format string:
+*
+*     pp_printf!(f, "@[<hov 2>let %s =@ %a@]", name, pp_expr, e);
+*
+* The format string is compiled into calls of the `BufPrinter` API. See the
+* `printf` module for the supported directives and conversions.
+*/
+#[proc_macro]
+pub fn pp_printf(input: TokenStream) -> TokenStream {
+    printf::expand(input.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/*
+* Derive `Pretty` for a struct or an enum. The fields accept the
+* `#[pretty(skip)]`, `#[pretty(rename = "name")]` and
+* `#[pretty(with = "path")]` attributes, see the `derive` module.
+*/
+#[proc_macro_derive(Pretty, attributes(pretty))]
+pub fn derive_pretty(input: TokenStream) -> TokenStream {
+    derive::expand(input.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,427 @@
+// Compilation of `pp_printf!` format strings.
+//
+// Pretty-printing directives:
+// - `@[` opens a box, `@[<kind n>` a box of the given kind and indentation,
+//   where kind is `h`, `v`, `hv`, `hov` or `b` (`@[<n>` is `@[<b n>`);
+// - `@]` closes the most recently opened box;
+// - `@,` is a cut, `@ ` a space, `@;` a full break `@;<width offset>`;
+// - `@\n` forces a newline in the current box;
+// - `@.` flushes the printer and ends the line, `@?` flushes it;
+// - `@{<tag>` opens a semantic tag, `@}` closes it;
+// - `@<n>` prints the next conversion or character as if it were `n`
+//   columns wide;
+// - `@@` and `@%` print a literal `@` and `%`.
+//
+// Conversions, with optional flags `-`, `0`, `+`, width and precision:
+// - `%s`, `%d`, `%i`, `%u`, `%f`, `%g`, `%c`, `%b`, `%B` print the argument
+//   with `Display`;
+// - `%x`, `%X`, `%o`, `%e`, `%E` print it in hexadecimal, octal or
+//   exponent notation;
+// - `%S`, `%C` print it with `Debug`, that is quoted;
+// - `%a` takes two arguments, a printer function `fn(&mut BufPrinter, &T)`
+//   and the value to print with it;
+// - `%t` takes a printer function `fn(&mut BufPrinter)`;
+// - `%%` prints a literal `%`, and `%!` flushes the printer.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Expr, LitStr, Token,
+};
+
+struct PrintfInput {
+    ppf: Expr,
+    fmt: LitStr,
+    args: Vec<Expr>,
+}
+
+impl Parse for PrintfInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ppf = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let fmt = input.parse()?;
+        let mut args = Vec::new();
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            let rest = Punctuated::<Expr, Token![,]>::parse_terminated(input)?;
+            args.extend(rest);
+        }
+        Ok(PrintfInput { ppf, fmt, args })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BoxKind {
+    H,
+    V,
+    Hv,
+    Hov,
+    B,
+}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+    Literal(String),
+    OpenBox(BoxKind, i32),
+    CloseBox,
+    Break(i32, i32),
+    ForceNewline,
+    Newline,
+    Flush,
+    OpenTag(String),
+    CloseTag,
+    PrintAs(usize),
+    // A conversion printing one argument with the given Rust format spec.
+    Format(String),
+    Custom,
+    Thunk,
+}
+
+// Parse a format string into items.
+fn parse_format(fmt: &str) -> Result<Vec<Item>, String> {
+    let chars: Vec<char> = fmt.chars().collect();
+    let mut items = Vec::new();
+    let mut literal = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c != '@' && c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let d = match chars.get(i) {
+            Some(d) => *d,
+            None => return Err(format!("incomplete directive `{}` at end of format", c)),
+        };
+        i += 1;
+        let item = if c == '@' {
+            match d {
+                '@' | '%' => {
+                    literal.push(d);
+                    continue;
+                }
+                '[' => {
+                    let (kind, indent) = match read_angle(&chars, &mut i) {
+                        None => (BoxKind::B, 0),
+                        Some(spec) => parse_box_spec(&spec)?,
+                    };
+                    Item::OpenBox(kind, indent)
+                }
+                ']' => Item::CloseBox,
+                ',' => Item::Break(0, 0),
+                ' ' => Item::Break(1, 0),
+                ';' => match read_angle(&chars, &mut i) {
+                    None => Item::Break(1, 0),
+                    Some(spec) => parse_break_spec(&spec)?,
+                },
+                '\n' => Item::ForceNewline,
+                '.' => Item::Newline,
+                '?' => Item::Flush,
+                '{' => match read_angle(&chars, &mut i) {
+                    None => return Err("expected `<tag>` after `@{`".to_string()),
+                    Some(tag) => Item::OpenTag(tag),
+                },
+                '}' => Item::CloseTag,
+                '<' => {
+                    i -= 1;
+                    let size = read_angle(&chars, &mut i).unwrap_or_default();
+                    match size.trim().parse() {
+                        Ok(n) => Item::PrintAs(n),
+                        Err(_) => return Err(format!("invalid size `@<{}>`", size)),
+                    }
+                }
+                _ => return Err(format!("unknown directive `@{}`", d)),
+            }
+        } else {
+            // Flags, width and precision come before the conversion.
+            let mut spec = String::new();
+            let mut d = d;
+            let mut align_left = false;
+            while d == '-' || d == '0' || d == '+' {
+                match d {
+                    '-' => align_left = true,
+                    '0' => spec.push('0'),
+                    _ => spec.insert(0, '+'),
+                }
+                d = next_char(&chars, &mut i)?;
+            }
+            if align_left {
+                // Left alignment overrides zero padding, as in C.
+                spec = spec.replace('0', "");
+                spec.insert(0, '<');
+            }
+            while d.is_ascii_digit() || d == '.' {
+                spec.push(d);
+                d = next_char(&chars, &mut i)?;
+            }
+            if !spec.is_empty() && matches!(d, '%' | '!' | 'a' | 't') {
+                return Err(format!("`%{}` takes no flags, width or precision", d));
+            }
+            match d {
+                '%' => {
+                    literal.push('%');
+                    continue;
+                }
+                '!' => Item::Flush,
+                'a' => Item::Custom,
+                't' => Item::Thunk,
+                's' | 'd' | 'i' | 'u' | 'f' | 'g' | 'c' | 'b' | 'B' => {
+                    Item::Format(rust_spec(&spec, ""))
+                }
+                'x' => Item::Format(rust_spec(&spec, "x")),
+                'X' => Item::Format(rust_spec(&spec, "X")),
+                'o' => Item::Format(rust_spec(&spec, "o")),
+                'e' => Item::Format(rust_spec(&spec, "e")),
+                'E' => Item::Format(rust_spec(&spec, "E")),
+                'S' | 'C' => Item::Format(rust_spec(&spec, "?")),
+                _ => return Err(format!("unknown conversion `%{}`", d)),
+            }
+        };
+        if !literal.is_empty() {
+            items.push(Item::Literal(std::mem::take(&mut literal)));
+        }
+        items.push(item);
+    }
+    if !literal.is_empty() {
+        items.push(Item::Literal(literal));
+    }
+    Ok(items)
+}
+
+fn next_char(chars: &[char], i: &mut usize) -> Result<char, String> {
+    match chars.get(*i) {
+        Some(c) => {
+            *i += 1;
+            Ok(*c)
+        }
+        None => Err("incomplete conversion at end of format".to_string()),
+    }
+}
+
+// Read an optional `<...>` argument of a directive.
+fn read_angle(chars: &[char], i: &mut usize) -> Option<String> {
+    if chars.get(*i) != Some(&'<') {
+        return None;
+    }
+    let close = chars[*i..].iter().position(|c| *c == '>')?;
+    let spec = chars[*i + 1..*i + close].iter().collect();
+    *i += close + 1;
+    Some(spec)
+}
+
+fn parse_int(s: &str) -> Result<i32, String> {
+    s.parse()
+        .map_err(|_| format!("expected an integer, found `{}`", s))
+}
+
+// A box specification, as OCaml reads it: a lowercase word for the kind of
+// box, then an optional indentation, with or without spaces in between.
+fn parse_box_spec(spec: &str) -> Result<(BoxKind, i32), String> {
+    let s = spec.trim_start();
+    let (word, indent) = s.split_at(s.find(|c: char| !c.is_ascii_lowercase()).unwrap_or(s.len()));
+    let kind = match word {
+        "" | "b" => BoxKind::B,
+        "h" => BoxKind::H,
+        "v" => BoxKind::V,
+        "hv" => BoxKind::Hv,
+        "hov" => BoxKind::Hov,
+        _ => return Err(format!("unknown box kind `{}`", word)),
+    };
+    let indent = match indent.trim() {
+        "" => 0,
+        n if n.contains(char::is_whitespace) => {
+            return Err(format!("invalid box specification `<{}>`", spec))
+        }
+        n => parse_int(n)?,
+    };
+    if indent < 0 {
+        return Err(format!("negative box indentation in `<{}>`", spec));
+    }
+    Ok((kind, indent))
+}
+
+fn parse_break_spec(spec: &str) -> Result<Item, String> {
+    let words: Vec<&str> = spec.split_whitespace().collect();
+    match words[..] {
+        [width, offset] => {
+            let width = parse_int(width)?;
+            if width < 0 {
+                return Err(format!("negative break width in `<{}>`", spec));
+            }
+            Ok(Item::Break(width, parse_int(offset)?))
+        }
+        _ => Err(format!("invalid break specification `<{}>`", spec)),
+    }
+}
+
+fn rust_spec(spec: &str, ty: &str) -> String {
+    if spec.is_empty() && ty.is_empty() {
+        "{}".to_string()
+    } else {
+        format!("{{:{}{}}}", spec, ty)
+    }
+}
+
+pub fn expand(input: TokenStream) -> syn::Result<TokenStream> {
+    let PrintfInput { ppf, fmt, args } = syn::parse2(input)?;
+    let items = parse_format(&fmt.value()).map_err(|msg| syn::Error::new(fmt.span(), msg))?;
+
+    let mut args = args.into_iter();
+    let mut next_arg = |what: &str| {
+        args.next()
+            .ok_or_else(|| syn::Error::new(fmt.span(), format!("missing argument for {}", what)))
+    };
+
+    let mut calls = Vec::new();
+    let mut print_as = None;
+    for item in items {
+        if let Some(size) = print_as.take() {
+            let call = match item {
+                Item::Literal(s) => {
+                    let mut chars = s.chars();
+                    let c = chars.next().unwrap().to_string();
+                    let rest = chars.as_str();
+                    if rest.is_empty() {
+                        quote! { __ppf.print_as(#size, #c); }
+                    } else {
+                        quote! {
+                            __ppf.print_as(#size, #c);
+                            __ppf.print_str(#rest);
+                        }
+                    }
+                }
+                Item::Format(spec) => {
+                    let arg = next_arg("a conversion")?;
+                    let spec = LitStr::new(&spec, Span::call_site());
+                    quote! { __ppf.print_as(#size, &::std::format!(#spec, #arg)); }
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        fmt.span(),
+                        "`@<n>` must be followed by a conversion or a character",
+                    ))
+                }
+            };
+            calls.push(call);
+            continue;
+        }
+        let call = match item {
+            Item::PrintAs(size) => {
+                print_as = Some(size);
+                continue;
+            }
+            Item::Literal(s) => quote! { __ppf.print_str(#s); },
+            Item::OpenBox(kind, indent) => {
+                let indent = indent as usize;
+                match kind {
+                    BoxKind::H => quote! { __ppf.open_hbox(); },
+                    BoxKind::V => quote! { __ppf.open_vbox(#indent); },
+                    BoxKind::Hv => quote! { __ppf.open_hvbox(#indent); },
+                    BoxKind::Hov => quote! { __ppf.open_hovbox(#indent); },
+                    BoxKind::B => quote! { __ppf.open_box(#indent); },
+                }
+            }
+            Item::CloseBox => quote! { __ppf.close_box(); },
+            Item::Break(0, 0) => quote! { __ppf.print_cut(); },
+            Item::Break(1, 0) => quote! { __ppf.print_space(); },
+            Item::Break(width, offset) if offset >= 0 => {
+                let (width, offset) = (width as usize, offset as usize);
+                quote! { __ppf.print_break(#width, #offset); }
+            }
+            Item::Break(width, offset) => quote! {
+                __ppf.print_custom_break(
+                    (::std::string::String::new(), #width, ::std::string::String::new()),
+                    (::std::string::String::new(), #offset, ::std::string::String::new()),
+                );
+            },
+            Item::ForceNewline => quote! { __ppf.force_newline(); },
+            Item::Newline => quote! { __ppf.print_newline(); },
+            Item::Flush => quote! { __ppf.print_flush(); },
+            Item::OpenTag(tag) => quote! { __ppf.open_stag(#tag); },
+            Item::CloseTag => quote! { __ppf.close_stag(); },
+            Item::Format(spec) => {
+                let arg = next_arg("a conversion")?;
+                let spec = LitStr::new(&spec, Span::call_site());
+                quote! { __ppf.print_str(::std::format!(#spec, #arg)); }
+            }
+            Item::Custom => {
+                let printer = next_arg("the printer of `%a`")?;
+                let value = next_arg("the value of `%a`")?;
+                quote! { (#printer)(&mut *__ppf, &#value); }
+            }
+            Item::Thunk => {
+                let printer = next_arg("`%t`")?;
+                quote! { (#printer)(&mut *__ppf); }
+            }
+        };
+        calls.push(call);
+    }
+    if print_as.is_some() {
+        return Err(syn::Error::new(
+            fmt.span(),
+            "`@<n>` must be followed by a conversion or a character",
+        ));
+    }
+    if let Some(extra) = args.next() {
+        return Err(syn::Error::new_spanned(
+            extra,
+            "argument never used by the format string",
+        ));
+    }
+
+    Ok(quote! {
+        {
+            let __ppf: &mut ::pp::BufPrinter<'_> = #ppf;
+            #(#calls)*
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(
+            parse_format("@[<hov 2>let %s =@ %a@]@;<1 -2>100%%@.").unwrap(),
+            [
+                Item::OpenBox(BoxKind::Hov, 2),
+                Item::Literal("let ".to_string()),
+                Item::Format("{}".to_string()),
+                Item::Literal(" =".to_string()),
+                Item::Break(1, 0),
+                Item::Custom,
+                Item::CloseBox,
+                Item::Break(1, -2),
+                Item::Literal("100%".to_string()),
+                Item::Newline,
+            ]
+        );
+        assert_eq!(
+            parse_format("@[<3>%-5.1f").unwrap()[1],
+            Item::Format("{:<5.1}".to_string())
+        );
+        assert_eq!(
+            parse_format("@<1>%s").unwrap(),
+            [Item::PrintAs(1), Item::Format("{}".to_string())]
+        );
+        assert_eq!(parse_format("a@\nb").unwrap().len(), 3);
+        assert_eq!(
+            parse_format("@[<hov2>@[<v>@[< 1>").unwrap(),
+            [
+                Item::OpenBox(BoxKind::Hov, 2),
+                Item::OpenBox(BoxKind::V, 0),
+                Item::OpenBox(BoxKind::B, 1),
+            ]
+        );
+        assert!(parse_format("@[<vh 2>").is_err());
+        assert!(parse_format("@[<hv 2 3>").is_err());
+        assert!(parse_format("%q").is_err());
+        assert!(parse_format("@").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// ANSI terminal styling on top of semantic tags.
+//
+// `AnsiStyler` is a set of tag-handling functions whose markers are SGR
+// escape sequences. Since markers are written directly to the output device,
+// the escapes take no room in the layout. Styles nest: closing a tag restores
+// the style of the enclosing tags, and the style is switched off around the
+// newline and indentation of a line break, then switched back on.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::tags::{Stag, TagFunctions};
+
+const RESET: &str = "\x1b[0m";
+
+// A terminal colour.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    // A colour of the 256-colour palette.
+    Ansi256(u8),
+    // A 24-bit colour.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    // Write the SGR parameters of the colour, as a foreground colour when
+    // `base` is 30 and as a background colour when it is 40.
+    fn write_sgr(self, base: u8, params: &mut Vec<String>) {
+        let bright = base + 60;
+        let code = match self {
+            Color::Black => base,
+            Color::Red => base + 1,
+            Color::Green => base + 2,
+            Color::Yellow => base + 3,
+            Color::Blue => base + 4,
+            Color::Magenta => base + 5,
+            Color::Cyan => base + 6,
+            Color::White => base + 7,
+            Color::BrightBlack => bright,
+            Color::BrightRed => bright + 1,
+            Color::BrightGreen => bright + 2,
+            Color::BrightYellow => bright + 3,
+            Color::BrightBlue => bright + 4,
+            Color::BrightMagenta => bright + 5,
+            Color::BrightCyan => bright + 6,
+            Color::BrightWhite => bright + 7,
+            Color::Ansi256(n) => {
+                params.push(format!("{};5;{}", base + 8, n));
+                return;
+            }
+            Color::Rgb(r, g, b) => {
+                params.push(format!("{};2;{};{};{}", base + 8, r, g, b));
+                return;
+            }
+        };
+        params.push(code.to_string());
+    }
+}
+
+/*
+* A text style. Styles are built from `Style::new()`:
+*
+*     Style::new().bold().fg(Color::Red)
+*
+* A nested style is combined with the enclosing one: its colours replace
+* the enclosing colours, its attributes are added to the enclosing ones.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    // The empty style, which changes nothing.
+    pub const fn new() -> Self {
+        Style {
+            fg: None,
+            bg: None,
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+        }
+    }
+
+    pub const fn fg(mut self, color: Color) -> Self {
+        self.fg = Some(color);
+        self
+    }
+
+    pub const fn bg(mut self, color: Color) -> Self {
+        self.bg = Some(color);
+        self
+    }
+
+    pub const fn bold(mut self) -> Self {
+        self.bold = true;
+        self
+    }
+
+    pub const fn dim(mut self) -> Self {
+        self.dim = true;
+        self
+    }
+
+    pub const fn italic(mut self) -> Self {
+        self.italic = true;
+        self
+    }
+
+    pub const fn underline(mut self) -> Self {
+        self.underline = true;
+        self
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == Style::new()
+    }
+
+    // The style `inner` nested inside `self`.
+    pub fn combine(&self, inner: &Style) -> Style {
+        Style {
+            fg: inner.fg.or(self.fg),
+            bg: inner.bg.or(self.bg),
+            bold: self.bold || inner.bold,
+            dim: self.dim || inner.dim,
+            italic: self.italic || inner.italic,
+            underline: self.underline || inner.underline,
+        }
+    }
+
+    // The escape sequence switching the style on, empty for a plain style.
+    pub fn escape(&self) -> String {
+        let mut params = Vec::new();
+        if self.bold {
+            params.push("1".to_string());
+        }
+        if self.dim {
+            params.push("2".to_string());
+        }
+        if self.italic {
+            params.push("3".to_string());
+        }
+        if self.underline {
+            params.push("4".to_string());
+        }
+        if let Some(fg) = self.fg {
+            fg.write_sgr(30, &mut params);
+        }
+        if let Some(bg) = self.bg {
+            bg.write_sgr(40, &mut params);
+        }
+        let mut s = String::new();
+        if !params.is_empty() {
+            let _ = write!(s, "\x1b[{}m", params.join(";"));
+        }
+        s
+    }
+}
+
+/*
+* Tag-handling functions writing ANSI escape sequences.
+*
+* A `Stag::Style` tag is output in its style; a named tag is output in the
+* style registered for its name with `set_style`, and unknown names leave
+* the style unchanged. When disabled, no escape sequence is written.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct AnsiStyler {
+    styles: HashMap<String, Style>,
+    // The combined styles of the currently marked tags.
+    stack: Vec<Style>,
+    disabled: bool,
+}
+
+impl AnsiStyler {
+    pub fn new() -> Self {
+        AnsiStyler::default()
+    }
+
+    // Register the style of the tags named `name`.
+    pub fn set_style(&mut self, name: &str, style: Style) {
+        self.styles.insert(name.to_string(), style);
+    }
+
+    // Register the style of the tags named `name`, builder-style.
+    pub fn with_style(mut self, name: &str, style: Style) -> Self {
+        self.set_style(name, style);
+        self
+    }
+
+    // Switch escape sequences on or off, for instance when the output is not
+    // a terminal.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.disabled = !enabled;
+    }
+
+    fn style_of(&self, tag: &Stag) -> Style {
+        match tag {
+            Stag::String(name) => self.styles.get(name).copied().unwrap_or_default(),
+            Stag::Style(style) => *style,
+        }
+    }
+
+    fn current(&self) -> Style {
+        self.stack.last().copied().unwrap_or_default()
+    }
+}
+
+impl TagFunctions for AnsiStyler {
+    fn mark_open(&mut self, tag: &Stag) -> String {
+        let style = self.current().combine(&self.style_of(tag));
+        self.stack.push(style);
+        if self.disabled {
+            String::new()
+        } else {
+            style.escape()
+        }
+    }
+
+    fn mark_close(&mut self, _tag: &Stag) -> String {
+        let closed = self.stack.pop().unwrap_or_default();
+        let current = self.current();
+        if self.disabled || closed == current {
+            String::new()
+        } else {
+            let mut s = RESET.to_string();
+            s.push_str(&current.escape());
+            s
+        }
+    }
+
+    fn mark_line_end(&mut self) -> String {
+        if self.disabled || self.current().is_plain() {
+            String::new()
+        } else {
+            RESET.to_string()
+        }
+    }
+
+    fn mark_line_start(&mut self) -> String {
+        if self.disabled {
+            String::new()
+        } else {
+            self.current().escape()
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// Structured builders, as the ones of `std::fmt::Formatter`.
+//
+//     ppf.pp_struct("Point").field("x", &x).field("y", &y).finish();
+//
+// The builders lay values out as `#[derive(Pretty)]` does, which uses them:
+// on one line as `{:?}` shows them when they fit, and otherwise as `{:#?}`
+// does, one entry per line, indented by 2, with a trailing comma. The
+// entries are in a `hvbox`, except for lists and sets set to fill the lines,
+// whose entries are in a `hovbox`. The trailing comma is the `breaks` string
+// of the closing custom break, so it only appears when the box is broken.
+
+use std::borrow::Cow;
+
+use crate::{BufPrinter, Pretty};
+
+// The layout shared by the builders: `open`, the entries separated by `,`
+// and breaks, and `close`; or `empty` when there are no entries.
+struct Delimited<'p, 'a> {
+    ppf: &'p mut BufPrinter<'a>,
+    open: Cow<'static, str>,
+    close: &'static str,
+    empty: Cow<'static, str>,
+    // Width of the breaks after `open` and before `close`.
+    space: usize,
+    fill: bool,
+    has_entries: bool,
+}
+
+impl<'p, 'a> Delimited<'p, 'a> {
+    fn new(
+        ppf: &'p mut BufPrinter<'a>,
+        open: Cow<'static, str>,
+        close: &'static str,
+        empty: Cow<'static, str>,
+        space: usize,
+    ) -> Self {
+        Delimited {
+            ppf,
+            open,
+            close,
+            empty,
+            space,
+            fill: false,
+            has_entries: false,
+        }
+    }
+
+    // Start an entry, the first one opening the boxes.
+    fn entry(&mut self) -> &mut BufPrinter<'a> {
+        if !self.has_entries {
+            self.has_entries = true;
+            self.ppf.open_hvbox(0);
+            self.ppf.print_str::<Cow<'a, str>>(self.open.clone());
+            self.ppf.print_break(self.space, 2);
+            if self.fill {
+                self.ppf.open_hovbox(0);
+            }
+        } else {
+            self.ppf.print_str(",");
+            if self.fill {
+                self.ppf.print_space();
+            } else {
+                self.ppf.print_break(1, 2);
+            }
+        }
+        self.ppf
+    }
+
+    // An entry printed as `label: value`, breaking after the label if the
+    // value does not fit.
+    fn labelled<L, F>(&mut self, label: L, value: F)
+    where
+        L: FnOnce(&mut BufPrinter<'a>),
+        F: FnOnce(&mut BufPrinter<'a>),
+    {
+        let ppf = self.entry();
+        ppf.open_hovbox(2);
+        label(ppf);
+        ppf.print_str(":");
+        ppf.print_space();
+        value(ppf);
+        ppf.close_box();
+    }
+
+    fn finish(&mut self) {
+        if !self.has_entries {
+            self.ppf.print_str::<Cow<'a, str>>(self.empty.clone());
+            return;
+        }
+        if self.fill {
+            self.ppf.close_box();
+        }
+        self.ppf.print_custom_break(
+            (String::new(), self.space as i32, String::new()),
+            (",".to_string(), 0, String::new()),
+        );
+        self.ppf.print_str(self.close);
+        self.ppf.close_box();
+    }
+}
+
+// A builder printing a struct as `Name { field: value, ... }`.
+pub struct PpStruct<'p, 'a>(Delimited<'p, 'a>);
+
+impl<'a> PpStruct<'_, 'a> {
+    pub fn field(&mut self, name: &str, value: &dyn Pretty) -> &mut Self {
+        self.field_with(name, |ppf| value.prettify(ppf))
+    }
+
+    // Add a field printed by a function, for values which are not Pretty.
+    pub fn field_with<F: FnOnce(&mut BufPrinter<'a>)>(
+        &mut self,
+        name: &str,
+        value: F,
+    ) -> &mut Self {
+        self.0.labelled(|ppf| ppf.print_string(name), value);
+        self
+    }
+
+    pub fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+// A builder printing a tuple struct as `Name(value, ...)`.
+pub struct PpTuple<'p, 'a>(Delimited<'p, 'a>);
+
+impl<'a> PpTuple<'_, 'a> {
+    pub fn field(&mut self, value: &dyn Pretty) -> &mut Self {
+        self.field_with(|ppf| value.prettify(ppf))
+    }
+
+    pub fn field_with<F: FnOnce(&mut BufPrinter<'a>)>(&mut self, value: F) -> &mut Self {
+        value(self.0.entry());
+        self
+    }
+
+    pub fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+// A builder printing a list as `[entry, ...]`, or a set as `{entry, ...}`.
+pub struct PpList<'p, 'a>(Delimited<'p, 'a>);
+
+impl<'a> PpList<'_, 'a> {
+    // Fill the lines with the entries instead of putting one per line when
+    // they do not all fit. Ignored once the list has entries, its boxes
+    // being open.
+    pub fn fill(&mut self, fill: bool) -> &mut Self {
+        if !self.0.has_entries {
+            self.0.fill = fill;
+        }
+        self
+    }
+
+    pub fn entry(&mut self, value: &dyn Pretty) -> &mut Self {
+        self.entry_with(|ppf| value.prettify(ppf))
+    }
+
+    pub fn entry_with<F: FnOnce(&mut BufPrinter<'a>)>(&mut self, value: F) -> &mut Self {
+        value(self.0.entry());
+        self
+    }
+
+    pub fn entries<T: Pretty, I: IntoIterator<Item = T>>(&mut self, entries: I) -> &mut Self {
+        for entry in entries {
+            self.entry(&entry);
+        }
+        self
+    }
+
+    pub fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+// A builder printing a map as `{key: value, ...}`.
+pub struct PpMap<'p, 'a>(Delimited<'p, 'a>);
+
+impl PpMap<'_, '_> {
+    pub fn entry(&mut self, key: &dyn Pretty, value: &dyn Pretty) -> &mut Self {
+        self.0
+            .labelled(|ppf| key.prettify(ppf), |ppf| value.prettify(ppf));
+        self
+    }
+
+    pub fn entries<K, V, I>(&mut self, entries: I) -> &mut Self
+    where
+        K: Pretty,
+        V: Pretty,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in entries {
+            self.entry(&key, &value);
+        }
+        self
+    }
+
+    pub fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+impl<'a> BufPrinter<'a> {
+    pub fn pp_struct<'p>(&'p mut self, name: &str) -> PpStruct<'p, 'a> {
+        let open = Cow::Owned(format!("{} {{", name));
+        let empty = Cow::Owned(name.to_string());
+        PpStruct(Delimited::new(self, open, "}", empty, 1))
+    }
+
+    pub fn pp_tuple<'p>(&'p mut self, name: &str) -> PpTuple<'p, 'a> {
+        let open = Cow::Owned(format!("{}(", name));
+        let empty = Cow::Owned(name.to_string());
+        PpTuple(Delimited::new(self, open, ")", empty, 0))
+    }
+
+    pub fn pp_list<'p>(&'p mut self) -> PpList<'p, 'a> {
+        PpList(Delimited::new(self, "[".into(), "]", "[]".into(), 0))
+    }
+
+    pub fn pp_set<'p>(&'p mut self) -> PpList<'p, 'a> {
+        PpList(Delimited::new(self, "{".into(), "}", "{}".into(), 0))
+    }
+
+    pub fn pp_map<'p>(&'p mut self) -> PpMap<'p, 'a> {
+        PpMap(Delimited::new(self, "{".into(), "}", "{}".into(), 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufPrinter;
+
+    #[test]
+    fn test_builders() {
+        let print = |margin: i32| {
+            let mut f = BufPrinter::new(margin, 4, margin - 4, i32::MAX);
+            f.open_hovbox(0);
+            let mut point = f.pp_struct("Point");
+            point.field("x", &1.5).field("y", &-2.0);
+            point.finish();
+            f.print_space();
+            f.pp_tuple("Pair").field(&"ab").field(&()).finish();
+            f.print_space();
+            f.pp_map().entry(&"k", &vec![1, 2]).finish();
+            f.print_space();
+            f.pp_list().fill(true).entries(1..=8).finish();
+            f.print_space();
+            f.pp_set().finish();
+            f.close_box();
+            f.flush_str()
+        };
+        assert_eq!(
+            print(90),
+            r#"Point { x: 1.5, y: -2.0 } Pair("ab", ()) {"k": [1, 2]} [1, 2, 3, 4, 5, 6, 7, 8] {}"#
+        );
+        assert_eq!(
+            print(16),
+            "Point {\n  x: 1.5,\n  y: -2.0,\n}\nPair(\"ab\", ())\n{\"k\": [1, 2]}\n[\n  1, 2, 3, 4,\n  5, 6, 7, 8,\n] {}"
+        );
+
+        // Filling set after the first entry leaves the enclosing box alone.
+        let mut f = BufPrinter::new(20, 4, 16, i32::MAX);
+        f.open_hvbox(4);
+        f.print_string("outer:");
+        f.pp_list().entry(&1).fill(true).entry(&2).finish();
+        f.print_space();
+        f.print_string("after the list");
+        f.print_space();
+        f.print_string("more words");
+        f.close_box();
+        assert_eq!(
+            f.flush_str(),
+            "outer:[1, 2]\n    after the list\n    more words"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+// Combinators printing sequences and optional values, as OCaml's
+// `pp_print_list`, `pp_print_seq`, `pp_print_iter`, `pp_print_option` and
+// `pp_print_result`.
+//
+// The separators are printers called between two elements, never after the
+// last one. `sep_cut` is OCaml's default; `sep_comma` gives the usual
+// `a, b, c` which breaks after the commas.
+
+use crate::{BufPrinter, Pretty};
+
+pub fn sep_cut(ppf: &mut BufPrinter<'_>) {
+    ppf.print_cut();
+}
+
+pub fn sep_space(ppf: &mut BufPrinter<'_>) {
+    ppf.print_space();
+}
+
+pub fn sep_comma(ppf: &mut BufPrinter<'_>) {
+    ppf.print_str(",");
+    ppf.print_space();
+}
+
+impl<'a> BufPrinter<'a> {
+    // Print the items with `print`, separated by `sep`.
+    pub fn print_list<T, I, S, F>(&mut self, mut sep: S, items: I, mut print: F)
+    where
+        I: IntoIterator<Item = T>,
+        S: FnMut(&mut Self),
+        F: FnMut(&mut Self, T),
+    {
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                sep(self);
+            }
+            print(self, item);
+        }
+    }
+
+    // Print values, separated by `sep`.
+    pub fn print_seq<T, I, S>(&mut self, sep: S, items: I)
+    where
+        T: Pretty,
+        I: IntoIterator<Item = T>,
+        S: FnMut(&mut Self),
+    {
+        self.print_list(sep, items, |ppf, item| item.prettify(ppf))
+    }
+
+    // Print the elements of a container which can only be iterated over
+    // internally: `iter` calls the function it is given on each element.
+    //
+    //     ppf.print_iter(sep_comma, |f| tree.for_each(f), |ppf, x| ...);
+    pub fn print_iter<T, It, S, F>(&mut self, mut sep: S, iter: It, mut print: F)
+    where
+        It: FnOnce(&mut dyn FnMut(T)),
+        S: FnMut(&mut Self),
+        F: FnMut(&mut Self, T),
+    {
+        let mut first = true;
+        iter(&mut |item| {
+            if !first {
+                sep(self);
+            }
+            first = false;
+            print(self, item);
+        });
+    }
+
+    // Print `Some` value with `print`, and `None` with `none`.
+    pub fn print_option<T, N, F>(&mut self, none: N, value: Option<T>, print: F)
+    where
+        N: FnOnce(&mut Self),
+        F: FnOnce(&mut Self, T),
+    {
+        match value {
+            None => none(self),
+            Some(value) => print(self, value),
+        }
+    }
+
+    // Print an `Ok` value with `ok`, and an `Err` one with `error`.
+    pub fn print_result<T, E, O, F>(&mut self, value: Result<T, E>, ok: O, error: F)
+    where
+        O: FnOnce(&mut Self, T),
+        F: FnOnce(&mut Self, E),
+    {
+        match value {
+            Ok(value) => ok(self, value),
+            Err(e) => error(self, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combinators() {
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        f.open_hovbox(1);
+        f.print_str("[");
+        f.print_list(sep_comma, ["alpha", "beta", "gamma", "delta"], |f, s| {
+            f.print_str(s)
+        });
+        f.print_str("]");
+        f.close_box();
+        assert_eq!(f.flush_str(), "[alpha, beta,\n gamma, delta]");
+
+        f.open_hbox();
+        f.print_seq(|f| f.print_str(";"), [1, 2, 3]);
+        f.print_space();
+        f.print_iter(
+            sep_space,
+            |each| "a b".split(' ').for_each(each),
+            |f, s| f.print_string(&s.to_uppercase()),
+        );
+        for value in [Some(1), None] {
+            f.print_space();
+            f.print_option(
+                |f| f.print_str("-"),
+                value,
+                |f, n| f.print_seq(sep_cut, [n]),
+            );
+        }
+        for value in [Ok(1), Err("e")] {
+            f.print_space();
+            f.print_result(
+                value,
+                |f, n| f.print_seq(sep_cut, [n]),
+                |f, e| f.print_str(e),
+            );
+        }
+        f.close_box();
+        assert_eq!(f.flush_str(), "1;2;3 A B 1 - 1 e");
+    }
+}
@@ -0,0 +1,518 @@
+// Documents in the style of Wadler and Leijen, printed with boxes.
+//
+//     let call = Doc::text("f(") + (Doc::softline() + args).nest(2) + Doc::text(")");
+//     ppf.print_doc(&call.group());
+//
+// A `Doc` is immutable and cheap to clone, so that documents can be shared,
+// stored and printed more than once, at any margin. Printing lowers the
+// document onto the engine: each `group` opens a box whose lines break
+// together, `fill` opens a compacting box, and lines become break hints.
+// Outside any group, lines other than softlines always break.
+//
+// A `softline` is a group of its own: the material from the previous line
+// of its group to the next one is put in a compacting box, which breaks each
+// softline only if what follows up to the next break does not fit.
+//
+// Boxes differ from the algebra in a few ways, and the lowering is then an
+// approximation:
+// - A box indents its lines relative to the column where it opens, not to
+//   the start of the line, so `nest` counts from the start of the innermost
+//   group, fill or align. Both agree for groups which begin their line, as
+//   they do in the usual bracketed layouts.
+// - A group, or the material after a softline, is measured up to its end
+//   and with its nested groups on one line, whereas the algebra measures up
+//   to the next line break, after the group or in a nested group which
+//   breaks. Both agree when groups end their line and a softline is not
+//   followed by a group which breaks.
+// - `align` opens a box, which breaks its lines together only if they do
+//   not fit, as its own group, rather than with the enclosing group.
+
+use std::{borrow::Cow, mem, ops::Add, rc::Rc};
+
+use crate::{BufPrinter, PpBox, Pretty};
+
+#[derive(Clone, Debug, Default)]
+pub struct Doc(Rc<DocNode>);
+
+#[derive(Debug, Default)]
+enum DocNode {
+    #[default]
+    Nil,
+    Text(Cow<'static, str>),
+    // A line break, or `width` spaces when flat.
+    Line {
+        width: usize,
+        soft: bool,
+    },
+    Concat(Doc, Doc),
+    Nest(usize, Doc),
+    Group(Doc),
+    Align(Doc),
+    Fill(Vec<Doc>),
+    // A line printed as the strings and spaces of `print_custom_break`.
+    CustomBreak {
+        fits: (String, usize, String),
+        breaks: (String, usize, String),
+    },
+}
+
+// Documents can be long chains of concatenations, hence no recursion when
+// dropping them either: the children only held by a node are moved onto a
+// work-list, and emptied there before being dropped.
+impl Drop for DocNode {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(doc) = stack.pop() {
+            if let Ok(mut node) = Rc::try_unwrap(doc.0) {
+                node.take_children(&mut stack);
+            }
+        }
+    }
+}
+
+impl DocNode {
+    fn take_children(&mut self, stack: &mut Vec<Doc>) {
+        match self {
+            DocNode::Nil
+            | DocNode::Text(_)
+            | DocNode::Line { .. }
+            | DocNode::CustomBreak { .. } => {}
+            DocNode::Concat(a, b) => {
+                stack.push(mem::take(a));
+                stack.push(mem::take(b));
+            }
+            DocNode::Nest(_, d) | DocNode::Group(d) | DocNode::Align(d) => stack.push(mem::take(d)),
+            DocNode::Fill(docs) => stack.append(docs),
+        }
+    }
+}
+
+impl Doc {
+    fn new(node: DocNode) -> Doc {
+        Doc(Rc::new(node))
+    }
+
+    pub fn nil() -> Doc {
+        Doc::default()
+    }
+
+    pub fn text<S: Into<Cow<'static, str>>>(s: S) -> Doc {
+        Doc::new(DocNode::Text(s.into()))
+    }
+
+    // A line break, or a space when flat.
+    pub fn line() -> Doc {
+        Doc::new(DocNode::Line {
+            width: 1,
+            soft: false,
+        })
+    }
+
+    // A line break, or nothing when flat.
+    pub fn line_() -> Doc {
+        Doc::new(DocNode::Line {
+            width: 0,
+            soft: false,
+        })
+    }
+
+    // A space, or a line break if what follows up to the next line does not
+    // fit: `line().group()` in the algebra.
+    pub fn softline() -> Doc {
+        Doc::new(DocNode::Line {
+            width: 1,
+            soft: true,
+        })
+    }
+
+    // Nothing, or a line break if what follows up to the next line does not
+    // fit.
+    pub fn softline_() -> Doc {
+        Doc::new(DocNode::Line {
+            width: 0,
+            soft: true,
+        })
+    }
+
+    pub fn concat<I: IntoIterator<Item = Doc>>(docs: I) -> Doc {
+        docs.into_iter().fold(Doc::nil(), |doc, d| doc + d)
+    }
+
+    // Fill the lines with the documents, separated by softlines.
+    pub fn fill<I: IntoIterator<Item = Doc>>(docs: I) -> Doc {
+        Doc::new(DocNode::Fill(docs.into_iter().collect()))
+    }
+
+    // Indent the lines of the document by `indent` more.
+    pub fn nest(self, indent: usize) -> Doc {
+        Doc::new(DocNode::Nest(indent, self))
+    }
+
+    // Print the document on one line if it fits, and otherwise break all
+    // its lines.
+    pub fn group(self) -> Doc {
+        Doc::new(DocNode::Group(self))
+    }
+
+    // Indent the lines of the document to the column where it starts.
+    pub fn align(self) -> Doc {
+        Doc::new(DocNode::Align(self))
+    }
+
+    // Indent the lines of the document past the column where it starts.
+    pub fn hang(self, indent: usize) -> Doc {
+        self.nest(indent).align()
+    }
+
+    // A line printed as `fits.0`, `fits.1` spaces and `fits.2` when its group
+    // is on one line, and otherwise as `breaks.0`, a line break indented by
+    // `breaks.1` more, and `breaks.2`. This is `flat_alt` in the algebra for
+    // the documents made of text and one line, such as a trailing comma:
+    // `Doc::custom_break(("", 0, ""), (",", 0, ""))`.
+    pub fn custom_break<S: Into<String>>(fits: (S, usize, S), breaks: (S, usize, S)) -> Doc {
+        Doc::new(DocNode::CustomBreak {
+            fits: (fits.0.into(), fits.1, fits.2.into()),
+            breaks: (breaks.0.into(), breaks.1, breaks.2.into()),
+        })
+    }
+}
+
+impl Add for Doc {
+    type Output = Doc;
+
+    fn add(self, rhs: Doc) -> Doc {
+        match (&*self.0, &*rhs.0) {
+            (DocNode::Nil, _) => rhs,
+            (_, DocNode::Nil) => self,
+            _ => Doc::new(DocNode::Concat(self, rhs)),
+        }
+    }
+}
+
+// What is left to print: documents with the indentation of their lines in
+// the current box, and the ends of boxes.
+enum Item<'d> {
+    Doc(&'d Doc, usize),
+    Sep(usize),
+    Close,
+}
+
+// An open box, and the indentation of the line which began its softline box,
+// if one is open.
+struct Level {
+    ty: PpBox,
+    soft: Option<usize>,
+}
+
+// Whether there is a softline from here to the next line of the current box,
+// the items being those left to print.
+fn soft_segment(items: &[Item<'_>]) -> bool {
+    for item in items.iter().rev() {
+        let mut stack = match item {
+            Item::Doc(doc, _) => vec![*doc],
+            Item::Sep(_) | Item::Close => return false,
+        };
+        while let Some(doc) = stack.pop() {
+            match &*doc.0 {
+                DocNode::Line { soft, .. } => return *soft,
+                DocNode::CustomBreak { .. } => return false,
+                DocNode::Concat(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+                DocNode::Nest(_, d) => stack.push(d),
+                DocNode::Nil
+                | DocNode::Text(_)
+                | DocNode::Group(_)
+                | DocNode::Align(_)
+                | DocNode::Fill(_) => {}
+            }
+        }
+    }
+    false
+}
+
+impl BufPrinter<'_> {
+    pub fn print_doc(&mut self, doc: &Doc) {
+        fn open(
+            ppf: &mut BufPrinter<'_>,
+            stack: &mut Vec<Item<'_>>,
+            boxes: &mut Vec<Level>,
+            ty: PpBox,
+        ) {
+            let _ = ppf.open_box_gen(0, ty);
+            boxes.push(Level { ty, soft: None });
+            stack.push(Item::Close);
+        }
+        // Close the softline box of the current box.
+        fn close_soft(ppf: &mut BufPrinter<'_>, boxes: &mut [Level]) {
+            if let Some(level) = boxes.last_mut() {
+                if level.soft.take().is_some() {
+                    ppf.close_box();
+                }
+            }
+        }
+        // Open a softline box if the material up to the next line of the
+        // current box has softlines, from a line indented by `indent`.
+        fn open_soft(
+            ppf: &mut BufPrinter<'_>,
+            stack: &[Item<'_>],
+            boxes: &mut [Level],
+            indent: usize,
+        ) {
+            if let Some(level) = boxes.last_mut() {
+                if !matches!(level.ty, PpBox::Hovbox()) && soft_segment(stack) {
+                    let _ = ppf.open_box_gen(0, PpBox::Hovbox());
+                    level.soft = Some(indent);
+                }
+            }
+        }
+        let offset = |n: usize| n.min(i32::MAX as usize) as i32;
+        // The open boxes, the type of the current one being kept by align.
+        // Documents can be long chains of concatenations, hence no recursion.
+        let mut boxes = Vec::new();
+        let mut stack = Vec::new();
+        open(self, &mut stack, &mut boxes, PpBox::Vbox());
+        stack.push(Item::Doc(doc, 0));
+        open_soft(self, &stack, &mut boxes, 0);
+        while let Some(item) = stack.pop() {
+            let (doc, indent) = match item {
+                Item::Doc(doc, indent) => (doc, indent),
+                Item::Sep(indent) => {
+                    self.print_break(1, indent);
+                    continue;
+                }
+                Item::Close => {
+                    close_soft(self, &mut boxes);
+                    self.close_box();
+                    boxes.pop();
+                    continue;
+                }
+            };
+            match &*doc.0 {
+                DocNode::Nil => {}
+                DocNode::Text(Cow::Borrowed(s)) => self.print_str(*s),
+                DocNode::Text(Cow::Owned(s)) => self.print_string(s),
+                DocNode::Line { width, soft: true } => {
+                    // Indented from the line which began the softline box.
+                    let base = boxes.last().and_then(|level| level.soft).unwrap_or(0);
+                    self.print_custom_break(
+                        (String::new(), offset(*width), String::new()),
+                        (String::new(), offset(indent) - offset(base), String::new()),
+                    )
+                }
+                DocNode::Line { width, soft: false } => {
+                    close_soft(self, &mut boxes);
+                    self.print_break(*width, indent);
+                    open_soft(self, &stack, &mut boxes, indent);
+                }
+                DocNode::CustomBreak { fits, breaks } => {
+                    close_soft(self, &mut boxes);
+                    self.print_custom_break(
+                        (fits.0.clone(), offset(fits.1), fits.2.clone()),
+                        (
+                            breaks.0.clone(),
+                            offset(indent + breaks.1),
+                            breaks.2.clone(),
+                        ),
+                    );
+                    open_soft(self, &stack, &mut boxes, indent + breaks.1);
+                }
+                DocNode::Concat(a, b) => {
+                    stack.push(Item::Doc(b, indent));
+                    stack.push(Item::Doc(a, indent));
+                }
+                DocNode::Nest(i, d) => stack.push(Item::Doc(d, indent + i)),
+                DocNode::Group(d) => {
+                    open(self, &mut stack, &mut boxes, PpBox::Hvbox());
+                    stack.push(Item::Doc(d, 0));
+                    open_soft(self, &stack, &mut boxes, 0);
+                }
+                DocNode::Align(d) => {
+                    let ty = boxes.last().map_or(PpBox::Vbox(), |level| level.ty);
+                    open(self, &mut stack, &mut boxes, ty);
+                    stack.push(Item::Doc(d, 0));
+                    open_soft(self, &stack, &mut boxes, 0);
+                }
+                DocNode::Fill(docs) => {
+                    open(self, &mut stack, &mut boxes, PpBox::Hovbox());
+                    for (i, d) in docs.iter().enumerate().rev() {
+                        stack.push(Item::Doc(d, 0));
+                        if i > 0 {
+                            stack.push(Item::Sep(0));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Pretty for Doc {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        ppf.print_doc(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pretty_to_string;
+
+    // The layout of a document in the algebra, as Leijen's printer gives
+    // it: a group, or a softline, is flat if it fits with what follows up
+    // to the next line break.
+    fn reference(doc: &Doc, width: usize) -> String {
+        // Whether the documents, flat or not, fit in `room` columns up to
+        // their first line break.
+        fn fits(mut room: isize, docs: &[(usize, bool, Doc)]) -> bool {
+            let mut stack = docs.to_vec();
+            while let Some((i, flat, doc)) = stack.pop() {
+                room -= match &*doc.0 {
+                    DocNode::Nil => 0,
+                    DocNode::Text(s) => s.len() as isize,
+                    DocNode::Line { width, .. } if flat => *width as isize,
+                    DocNode::CustomBreak { fits, .. } if flat => {
+                        (fits.0.len() + fits.1 + fits.2.len()) as isize
+                    }
+                    DocNode::Line { .. } | DocNode::CustomBreak { .. } => return true,
+                    DocNode::Concat(a, b) => {
+                        stack.push((i, flat, b.clone()));
+                        stack.push((i, flat, a.clone()));
+                        0
+                    }
+                    DocNode::Nest(_, d) | DocNode::Group(d) | DocNode::Align(d) => {
+                        stack.push((i, flat, d.clone()));
+                        0
+                    }
+                    DocNode::Fill(docs) => {
+                        stack.push((i, flat, fill_docs(docs)));
+                        0
+                    }
+                };
+                if room < 0 {
+                    return false;
+                }
+            }
+            true
+        }
+        fn fill_docs(docs: &[Doc]) -> Doc {
+            let mut doc = Doc::nil();
+            for (i, d) in docs.iter().enumerate() {
+                if i > 0 {
+                    doc = doc + Doc::softline();
+                }
+                doc = doc + d.clone();
+            }
+            doc
+        }
+        let mut out = String::new();
+        let mut column = 0;
+        let mut stack = vec![(0, false, doc.clone())];
+        while let Some((i, flat, doc)) = stack.pop() {
+            let room = width as isize - column as isize;
+            match &*doc.0 {
+                DocNode::Nil => {}
+                DocNode::Text(s) => {
+                    out.push_str(s);
+                    column += s.len();
+                }
+                DocNode::Line { width, soft } => {
+                    let mut rest = stack.clone();
+                    rest.push((i, true, doc.clone()));
+                    if flat || *soft && fits(room, &rest) {
+                        out.push_str(&" ".repeat(*width));
+                        column += width;
+                    } else {
+                        out.push('\n');
+                        out.push_str(&" ".repeat(i));
+                        column = i;
+                    }
+                }
+                DocNode::CustomBreak { fits, breaks } => {
+                    if flat {
+                        out.push_str(&format!("{}{}{}", fits.0, " ".repeat(fits.1), fits.2));
+                        column += fits.0.len() + fits.1 + fits.2.len();
+                    } else {
+                        out.push_str(&breaks.0);
+                        out.push('\n');
+                        out.push_str(&" ".repeat(i + breaks.1));
+                        out.push_str(&breaks.2);
+                        column = i + breaks.1 + breaks.2.len();
+                    }
+                }
+                DocNode::Concat(a, b) => {
+                    stack.push((i, flat, b.clone()));
+                    stack.push((i, flat, a.clone()));
+                }
+                DocNode::Nest(j, d) => stack.push((i + j, flat, d.clone())),
+                DocNode::Align(d) => stack.push((column, flat, d.clone())),
+                DocNode::Group(d) => {
+                    let mut rest = stack.clone();
+                    rest.push((i, true, d.clone()));
+                    let flat = flat || fits(room, &rest);
+                    stack.push((i, flat, d.clone()));
+                }
+                DocNode::Fill(docs) => stack.push((i, flat, fill_docs(docs))),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_doc() {
+        // A call whose arguments fill the lines, and a list broken all
+        // together, with a trailing comma when broken.
+        let args = Doc::fill((1..=6).map(|n| Doc::text(format!("arg{},", n))));
+        let call = Doc::text("f(") + (Doc::line_() + args).nest(2) + Doc::line_() + Doc::text(")");
+        let comma = Doc::custom_break((",", 1, ""), (",", 0, ""));
+        let items = Doc::concat(
+            ["xs", "ys", "zs"]
+                .map(Doc::text)
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| if i > 0 { comma.clone() + item } else { item }),
+        );
+        let trailing = Doc::custom_break(("", 0, ""), (",", 0, ""));
+        let list = Doc::text("[") + (Doc::line_() + items).nest(4) + trailing + Doc::text("]");
+        let binding = Doc::text("let a =") + Doc::softline() + list.group().align();
+        let doc = binding.group() + Doc::line() + call.clone().group();
+
+        assert_eq!(
+            pretty_to_string(&doc, 80),
+            "let a = [xs, ys, zs]\nf(arg1, arg2, arg3, arg4, arg5, arg6,)"
+        );
+        assert_eq!(
+            pretty_to_string(&doc, 20),
+            "let a =\n[xs, ys, zs]\nf(\n  arg1, arg2, arg3,\n  arg4, arg5, arg6,\n)"
+        );
+        assert_eq!(
+            pretty_to_string(&doc, 12),
+            "let a =\n[\n    xs,\n    ys,\n    zs,\n]\nf(\n  arg1,\n  arg2,\n  arg3,\n  arg4,\n  arg5,\n  arg6,\n)"
+        );
+
+        // The layouts of the algebra, the engine keeping the lines below the
+        // margin, for groups which begin and end their lines.
+        let soft = Doc::text("aaaa") + Doc::line() + Doc::text("bbbb") + Doc::softline();
+        let soft = (soft + Doc::text("cccc")).group();
+        assert_eq!(pretty_to_string(&soft, 10), "aaaa\nbbbb cccc");
+        let words = ["the", "quick", "brown", "fox", "jumps"].map(Doc::text);
+        let words = Doc::concat(words.into_iter().enumerate().map(|(i, word)| match i {
+            0 => word,
+            2 => Doc::line() + word,
+            _ => Doc::softline() + word,
+        }));
+        let body = Doc::text("words:") + (Doc::line() + words).nest(2);
+        for doc in [soft, body.group(), call.group()] {
+            for margin in 6..40 {
+                assert_eq!(
+                    pretty_to_string(&doc, margin as i32),
+                    reference(&doc, margin - 1),
+                    "at margin {}",
+                    margin
+                );
+            }
+        }
+    }
+}
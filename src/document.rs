@@ -0,0 +1,164 @@
+// Documents recorded from a pretty-printer, to be laid out again at any
+// margin.
+//
+//     let doc = Document::record(|ppf| value.prettify(ppf));
+//     let narrow = pretty_to_string(&doc, 40);
+//     let wide = pretty_to_string(&doc, 120);
+//
+// The pretty-printer consumes its tokens as it formats them, so a recording
+// keeps a copy of each token enqueued, together with its length, and the
+// tags opened and closed. Printing the document enqueues them again, through
+// the same functions as the printing calls which produced them: boxes,
+// breaks and sizes are all computed anew, for the margin and geometry of the
+// printer, without going back to the data the document was made from.
+//
+// Flushing while recording closes the open boxes, which is recorded, and the
+// newline ending a flush, as by `print_newline`, is recorded as a forced
+// newline; the flushes themselves are not. The tokens not enqueued past the
+// maximum number of boxes are not recorded either, only the ellipsis printed
+// instead.
+
+use std::borrow::Cow;
+
+use crate::{BufPrinter, FnOutput, PpQueueElem, PpToken, Pretty, Size};
+
+#[derive(Clone, Debug, Default)]
+pub struct Document {
+    tokens: Vec<(PpToken<'static>, i32)>,
+}
+
+impl Document {
+    // Record what `print` prints, on a printer which only records it,
+    // without laying it out.
+    pub fn record<F: FnOnce(&mut BufPrinter<'_>)>(print: F) -> Document {
+        let out = FnOutput::new(|_| (), || ());
+        let mut ppf = BufPrinter::with_output(78, 10, 68, i32::MAX, out);
+        ppf.record_only = true;
+        ppf.start_recording();
+        print(&mut ppf);
+        ppf.stop_recording()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl<'a> BufPrinter<'a> {
+    // Record the material printed from now on, discarding any recording
+    // in progress.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    // Stop recording, and take what was recorded.
+    pub fn stop_recording(&mut self) -> Document {
+        Document {
+            tokens: self.recording.take().unwrap_or_default(),
+        }
+    }
+
+    // Print a recorded document, borrowing its text.
+    pub fn print_document(&mut self, doc: &'a Document) {
+        self.replay(doc, Cow::Borrowed)
+    }
+
+    fn replay<'d, F>(&mut self, doc: &'d Document, text: F)
+    where
+        F: Fn(&'d str) -> Cow<'a, str>,
+    {
+        for (token, length) in &doc.tokens {
+            let length = *length;
+            match token {
+                PpToken::Text(s) => {
+                    let _ = self.print_as_size(Size(length), text(s));
+                }
+                PpToken::Break(width, offset) => {
+                    let _ = self.scan_break(PpToken::Break(*width, *offset), length);
+                }
+                PpToken::CustomBreak(custom) => {
+                    let _ = self.scan_break(PpToken::CustomBreak(custom.clone()), length);
+                }
+                PpToken::TBreak(width, offset) => {
+                    let _ = self.scan_break(PpToken::TBreak(*width, *offset), length);
+                }
+                PpToken::Begin(indent, ty) => {
+                    let _ = self.open_box_gen(*indent, *ty);
+                }
+                PpToken::End() => self.close_box(),
+                PpToken::TBegin() => self.open_tbox(),
+                PpToken::TEnd() => self.close_tbox(),
+                PpToken::Newline() => self.force_newline(),
+                PpToken::IfNewline() => self.print_if_newline(),
+                PpToken::SetTab() => self.set_tab(),
+                PpToken::OpenTag(tag) => self.open_stag(tag.clone()),
+                PpToken::CloseTag() => self.close_stag(),
+                PpToken::Paragraph(paragraph) => {
+                    if self.curr_depth < self.max_boxes {
+                        let _ = self.enqueue_advance(PpQueueElem {
+                            size: Size(length),
+                            token: PpToken::Paragraph(paragraph.clone()),
+                            length,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Pretty for Document {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        ppf.replay(self, |s| Cow::Owned(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pp_printf, pretty_to_string, BufPrinter, Justify};
+
+    #[test]
+    fn test_document() {
+        let doc = Document::record(|ppf| {
+            ppf.open_hovbox(0);
+            ppf.open_stag("list");
+            ppf.pp_list().entries(["alpha", "beta", "gamma"]).finish();
+            ppf.close_stag();
+            ppf.print_space();
+            ppf.print_paragraph("one two three", Justify::Left);
+            ppf.close_box();
+        });
+        assert!(!doc.is_empty());
+        assert_eq!(
+            pretty_to_string(&doc, 80),
+            r#"["alpha", "beta", "gamma"] one two three"#
+        );
+        assert_eq!(
+            pretty_to_string(&doc, 20),
+            "[\n  \"alpha\",\n  \"beta\",\n  \"gamma\",\n] one two three"
+        );
+
+        // Printing with tags marked, and recording what is printed.
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        f.set_mark_tags(true);
+        f.start_recording();
+        f.open_hovbox(0);
+        f.print_document(&doc);
+        f.close_box();
+        let again = f.stop_recording();
+        assert_eq!(
+            f.flush_str(),
+            "<list>[\n  \"alpha\",\n  \"beta\",\n  \"gamma\",\n]</list> one two three"
+        );
+        assert_eq!(pretty_to_string(&again, 80), pretty_to_string(&doc, 80));
+
+        // Explicit line ends are kept.
+        let doc = Document::record(|f| {
+            pp_printf!(f, "@[<h>a@ b@]@.c@.");
+            // Nothing is enqueued to be laid out while recording.
+            assert_eq!((f.right_total, f.queue.len()), (1, 0));
+        });
+        assert_eq!(pretty_to_string(&doc, 80), "a b\nc\n");
+    }
+}
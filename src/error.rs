@@ -0,0 +1,31 @@
+// The errors of the pretty-printing engine.
+//
+// The plain printing functions never fail: as in OCaml, sizes are limited
+// below the infinity of the engine and closing a box which is not open does
+// nothing. Their `try_` variants report these cases instead.
+
+use std::{error, fmt};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PpError {
+    // A size or an indentation is not below the infinity of the engine, or
+    // the total width of the material overflows.
+    Overflow,
+    // A box or tabulation box is closed while none is open.
+    NoOpenBox,
+    // An internal invariant of the engine does not hold.
+    Invariant(&'static str),
+}
+
+impl fmt::Display for PpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PpError::Overflow => f.write_str("size overflows the pretty-printer"),
+            PpError::NoOpenBox => f.write_str("no box to close"),
+            PpError::Invariant(what) => write!(f, "pretty-printer invariant broken: {}", what),
+        }
+    }
+}
+
+impl error::Error for PpError {}
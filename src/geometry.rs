@@ -0,0 +1,215 @@
+// The geometry of a pretty-printer, as OCaml's `Format.geometry`.
+//
+// The margin is the width of the lines, and the maximum indentation the
+// column past which boxes are no longer opened at their insertion point but
+// on a new line. The minimum space left is their difference: a box opened
+// with less room than this before the margin is rejected to the left.
+//
+// The setters follow OCaml: out of range values are ignored, too large ones
+// are limited below the infinity, and a change of geometry reinitialises the
+// pretty-printer, discarding any pending material.
+
+use anyhow::bail;
+
+use crate::{pp_limit, BufPrinter, INFINITY};
+
+// The margin and maximum indentation of a pretty-printer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Geometry {
+    pub max_indent: usize,
+    pub margin: usize,
+}
+
+impl Default for Geometry {
+    // The geometry of a pretty-printer set up as OCaml's `std_formatter`.
+    fn default() -> Self {
+        Geometry {
+            max_indent: 68,
+            margin: 78,
+        }
+    }
+}
+
+pub(crate) fn validate_geometry(geometry: &Geometry) -> anyhow::Result<()> {
+    if geometry.max_indent < 2 {
+        bail!("max_indent < 2")
+    } else if geometry.margin <= geometry.max_indent {
+        bail!("margin <= max_indent")
+    } else if geometry.margin >= INFINITY as usize {
+        bail!("margin >= pp_infinity")
+    }
+    Ok(())
+}
+
+// Check that a geometry can be set without error, that is
+// `2 <= max_indent < margin < INFINITY`.
+pub fn check_geometry(geometry: &Geometry) -> bool {
+    validate_geometry(geometry).is_ok()
+}
+
+impl BufPrinter<'_> {
+    // Set the minimum space left before the margin, hence the maximum
+    // indentation. Values below 1 are ignored.
+    pub fn set_min_space_left(&mut self, n: usize) {
+        if n >= 1 {
+            self.min_space_left = pp_limit(n);
+            self.max_indent = self.margin - self.min_space_left;
+            self.rinit();
+        }
+    }
+
+    // Set the maximum indentation. Values below 2 are ignored, as are values
+    // not below the margin.
+    pub fn set_max_indent(&mut self, n: usize) {
+        if n > 1 {
+            if let Ok(n) = usize::try_from(self.margin - pp_limit(n)) {
+                self.set_min_space_left(n);
+            }
+        }
+    }
+
+    pub fn get_max_indent(&self) -> usize {
+        self.max_indent as usize
+    }
+
+    // Set the margin. Values below 1 are ignored. The maximum indentation is
+    // kept if it still fits within the new margin, and is otherwise brought
+    // back to `margin - min_space_left`, or half the margin if that is more.
+    pub fn set_margin(&mut self, n: usize) {
+        if n >= 1 {
+            self.margin = pp_limit(n);
+            let new_max_indent = if self.max_indent <= self.margin {
+                self.max_indent
+            } else {
+                (self.margin - self.min_space_left)
+                    .max(self.margin / 2)
+                    .max(1)
+            };
+            self.set_max_indent(new_max_indent as usize);
+        }
+    }
+
+    pub fn get_margin(&self) -> usize {
+        self.margin as usize
+    }
+
+    fn set_full_geometry(&mut self, geometry: &Geometry) {
+        self.set_margin(geometry.margin);
+        self.set_max_indent(geometry.max_indent);
+    }
+
+    // Set the margin and maximum indentation together, failing if the
+    // geometry does not pass `check_geometry`.
+    pub fn set_geometry(&mut self, geometry: &Geometry) -> anyhow::Result<()> {
+        if let Err(e) = validate_geometry(geometry) {
+            bail!("set_geometry: {}", e)
+        }
+        self.set_full_geometry(geometry);
+        Ok(())
+    }
+
+    // Set the geometry if it passes `check_geometry`, and otherwise leave
+    // the pretty-printer unchanged.
+    pub fn safe_set_geometry(&mut self, geometry: &Geometry) {
+        if check_geometry(geometry) {
+            self.set_full_geometry(geometry)
+        }
+    }
+
+    pub fn get_geometry(&self) -> Geometry {
+        Geometry {
+            max_indent: self.get_max_indent(),
+            margin: self.get_margin(),
+        }
+    }
+
+    // Change the geometry with `update`, without checking the result.
+    pub fn update_geometry<F: FnOnce(Geometry) -> Geometry>(&mut self, update: F) {
+        let geometry = update(self.get_geometry());
+        self.set_full_geometry(&geometry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geometry() {
+        let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+        assert_eq!(f.get_geometry(), Geometry::default());
+
+        let bad = [(1, 78), (40, 40), (50, 40), (2, INFINITY as usize)];
+        for (max_indent, margin) in bad {
+            let geometry = Geometry { max_indent, margin };
+            assert!(!check_geometry(&geometry));
+            assert!(f.set_geometry(&geometry).is_err());
+            f.safe_set_geometry(&geometry);
+            assert_eq!(f.get_geometry(), Geometry::default());
+        }
+
+        let narrow = Geometry {
+            max_indent: 10,
+            margin: 20,
+        };
+        f.set_geometry(&narrow).unwrap();
+        assert_eq!(f.get_geometry(), narrow);
+
+        // Shrinking the margin below the maximum indentation brings the
+        // indentation back within it; out of range values are ignored.
+        f.set_margin(8);
+        assert_eq!(f.get_geometry().max_indent, 4);
+        f.set_margin(0);
+        f.set_max_indent(1);
+        f.set_max_indent(8);
+        assert_eq!(
+            f.get_geometry(),
+            Geometry {
+                max_indent: 4,
+                margin: 8
+            }
+        );
+        f.set_margin(usize::MAX);
+        assert_eq!(f.get_margin(), INFINITY as usize - 1);
+
+        f.set_geometry(&narrow).unwrap();
+        f.open_box(0);
+        for word in ["abc", "def", "ghi", "jkl", "mno", "pqr"] {
+            f.print_string(word);
+            f.print_space();
+        }
+        f.close_box();
+        assert_eq!(f.flush_str(), "abc def ghi jkl mno\npqr ");
+
+        // `with_geometry` checks the geometry and the maximum number of
+        // boxes, deriving the minimum space left.
+        let f = BufPrinter::with_geometry(&narrow, 100, String::new()).unwrap();
+        assert_eq!((f.get_geometry(), f.min_space_left), (narrow, 10));
+        for (max_indent, margin) in bad {
+            let geometry = Geometry { max_indent, margin };
+            assert!(BufPrinter::with_geometry(&geometry, 100, String::new()).is_err());
+        }
+        assert!(BufPrinter::with_geometry(&narrow, 0, String::new()).is_err());
+
+        // `new` sets them as the setters do, whatever the values.
+        let f = BufPrinter::new(80, 10, 60, 100);
+        let geometry = Geometry {
+            max_indent: 60,
+            margin: 80,
+        };
+        assert_eq!((f.get_geometry(), f.min_space_left), (geometry, 20));
+        let f = BufPrinter::new(20, 0, -1, 100);
+        assert_eq!(f.get_geometry().max_indent, 10);
+        for (margin, min_space_left, max_indent, max_boxes) in
+            [(2, 1, 1, 100), (-1, 10, -11, 100), (78, 10, 68, 0)]
+        {
+            let mut f = BufPrinter::new(margin, min_space_left, max_indent, max_boxes);
+            f.open_box(0);
+            f.print_string("abc");
+            f.print_space();
+            f.print_string("def");
+            f.close_box();
+            assert!(f.flush_str().starts_with("abc"));
+        }
+    }
+}
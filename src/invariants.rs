@@ -0,0 +1,407 @@
+// Invariants of the layout, checked on random documents.
+//
+// Each document is a tree of boxes, texts and break hints, printed on a
+// printer of random geometry writing to a `SymbolicOutput`. The output items
+// are then matched against the document: every text in order, each break
+// hint as either its spaces or a newline and an indentation, and nothing
+// else but the newlines of boxes rejected to the left past the maximum
+// indentation. The checks are:
+// - an hbox never breaks its hints, and a vbox breaks at every hint;
+// - an hvbox breaks at all its hints or at none;
+// - removing the newlines and indentations, and putting back the spaces of
+//   the broken hints, gives the text as printed on one line;
+// - a line only exceeds the margin if the engine kept no hint on it which it
+//   could have broken, the rest being texts and hints of hboxes, and hints
+//   of structural boxes before any text on the line, which never break;
+// - after the flush, the queue and its totals are back to their initial
+//   state.
+//
+// Two layouts of OCaml's Format keep such a hint on a line past the margin,
+// and the documents have both:
+// - The last hint of a box is measured up to the end of the box only, so
+//   material after the end, text or the hints of an enclosing hbox, may
+//   overflow the line: the box of the last hint kept on the line ends on it
+//   within the margin.
+// - A box fitting on one line keeps all its hints, even when a nested box
+//   breaks a line of it, as a vbox does: a nested box broke a line of the
+//   box of the last hint kept, before that hint.
+// The test checks that each of the three kinds of lines past the margin
+// shows up.
+//
+// A failure reports the seed of the document, which `check_seed` replays.
+
+use crate::{BufPrinter, OutputItem, SymbolicOutput};
+
+// A xorshift generator, enough for test data.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // A number in `lo..=hi`.
+    fn range(&mut self, lo: usize, hi: usize) -> usize {
+        lo + (self.next() % (hi - lo + 1) as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    H,
+    V,
+    Hv,
+    Hov,
+    B,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Break(usize, usize),
+    Box(Kind, usize, Vec<Node>),
+}
+
+fn gen_box(rng: &mut Rng, depth: usize) -> Node {
+    let kind = [Kind::H, Kind::V, Kind::Hv, Kind::Hov, Kind::B][rng.range(0, 4)];
+    let mut children = Vec::new();
+    for _ in 0..rng.range(0, 6) {
+        match rng.range(0, 9) {
+            0..=3 => {
+                // Mostly short words, and now and then a long one.
+                let len = if rng.chance(5) {
+                    rng.range(10, 30)
+                } else {
+                    rng.range(1, 6)
+                };
+                let c = (b'a' + rng.range(0, 25) as u8) as char;
+                children.push(Node::Text(c.to_string().repeat(len)));
+            }
+            4..=6 => children.push(Node::Break(rng.range(1, 3), rng.range(0, 4))),
+            _ if depth < 4 => children.push(gen_box(rng, depth + 1)),
+            _ => {}
+        }
+    }
+    Node::Box(kind, rng.range(0, 4), children)
+}
+
+fn print(f: &mut BufPrinter<'_>, node: &Node) {
+    match node {
+        Node::Text(s) => f.print_string(s),
+        Node::Break(width, offset) => f.print_break(*width, *offset),
+        Node::Box(kind, indent, children) => {
+            match kind {
+                Kind::H => f.open_hbox(),
+                Kind::V => f.open_vbox(*indent),
+                Kind::Hv => f.open_hvbox(*indent),
+                Kind::Hov => f.open_hovbox(*indent),
+                Kind::B => f.open_box(*indent),
+            }
+            for child in children {
+                print(f, child);
+            }
+            f.close_box();
+        }
+    }
+}
+
+// The document on one line.
+fn flat(node: &Node, text: &mut String) {
+    match node {
+        Node::Text(s) => text.push_str(s),
+        Node::Break(width, _) => text.push_str(&" ".repeat(*width)),
+        Node::Box(_, _, children) => children.iter().for_each(|child| flat(child, text)),
+    }
+}
+
+// The document as a sequence of events.
+enum Event<'d> {
+    Text(&'d str),
+    Break(usize),
+    Open(Kind),
+    Close,
+}
+
+fn events<'d>(node: &'d Node, events: &mut Vec<Event<'d>>) {
+    match node {
+        Node::Text(s) => events.push(Event::Text(s)),
+        Node::Break(width, _) => events.push(Event::Break(*width)),
+        Node::Box(kind, _, children) => {
+            events.push(Event::Open(*kind));
+            children
+                .iter()
+                .for_each(|child| self::events(child, events));
+            events.push(Event::Close);
+        }
+    }
+}
+
+// Why a line may exceed the margin: it has no hint the engine could have
+// broken, or the box of the last one ends on the line, or a nested box broke
+// a line of that box before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Overflow {
+    Unbreakable,
+    BoxEnd,
+    NestedNewline,
+}
+
+// What the walk notes on the current line.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    // A hint kept on the line which the engine could have broken, with the
+    // number of its box, and whether a nested box broke a line of that box
+    // before.
+    Kept(usize, bool),
+    // The end of the box of that number, at that column.
+    Close(usize, usize),
+}
+
+#[derive(Clone)]
+struct OpenBox {
+    kind: Kind,
+    id: usize,
+    // Whether each hint broke the line.
+    hints: Vec<bool>,
+    nested_newline: bool,
+}
+
+// The output items, consumed while walking the events of the document.
+#[derive(Clone)]
+struct Items<'i> {
+    items: &'i [OutputItem],
+    flat: String,
+    column: usize,
+    margin: usize,
+    max_indent: usize,
+    boxes: Vec<OpenBox>,
+    opened: usize,
+    // Whether text was printed since the last newline.
+    text: bool,
+    marks: Vec<Mark>,
+    overflows: Vec<Overflow>,
+}
+
+impl Items<'_> {
+    // Check the line ending here, past the margin or not.
+    fn end_line(&mut self) -> Result<(), String> {
+        if self.column <= self.margin {
+            return Ok(());
+        }
+        let kept = self.marks.iter().rposition(|m| matches!(m, Mark::Kept(..)));
+        let overflow = match kept.map(|i| (self.marks[i], &self.marks[i + 1..])) {
+            None => Overflow::Unbreakable,
+            Some((Mark::Kept(id, _), after))
+                if after.iter().any(
+                    |m| matches!(*m, Mark::Close(i, column) if i == id && column <= self.margin),
+                ) =>
+            {
+                Overflow::BoxEnd
+            }
+            Some((Mark::Kept(_, true), _)) => Overflow::NestedNewline,
+            _ => {
+                return Err(format!(
+                    "line of width {} past margin {} with a hint kept",
+                    self.column, self.margin
+                ))
+            }
+        };
+        self.overflows.push(overflow);
+        Ok(())
+    }
+
+    // Take a newline and the indentation after it, if they come next.
+    fn newline(&mut self) -> Result<bool, String> {
+        let [OutputItem::Newline, rest @ ..] = self.items else {
+            return Ok(false);
+        };
+        self.end_line()?;
+        self.items = rest;
+        self.column = 0;
+        if let [OutputItem::Indent(n), rest @ ..] = self.items {
+            self.items = rest;
+            self.column = *n;
+        }
+        self.text = false;
+        self.marks.clear();
+        Ok(true)
+    }
+
+    // Match all the items with the events.
+    fn walk(mut self, events: &[Event<'_>]) -> Result<Self, String> {
+        for (i, event) in events.iter().enumerate() {
+            match event {
+                Event::Text(s) => {
+                    match self.items {
+                        [OutputItem::String(t), rest @ ..] if t == s => self.items = rest,
+                        items => return Err(format!("text {:?} at {:?}", s, items.first())),
+                    }
+                    self.flat.push_str(s);
+                    self.column += s.len();
+                    self.text = true;
+                }
+                Event::Break(width) => {
+                    let broken = self.newline()?;
+                    let depth = self.boxes.len();
+                    if broken {
+                        // A newline of the enclosing boxes too.
+                        for open in &mut self.boxes[..depth.saturating_sub(1)] {
+                            open.nested_newline = true;
+                        }
+                    } else {
+                        match self.items {
+                            [OutputItem::Spaces(n), rest @ ..] if n == width => {
+                                self.items = rest;
+                                self.column += n;
+                            }
+                            items => return Err(format!("break {} at {:?}", width, items.first())),
+                        }
+                    }
+                    if let Some(open) = self.boxes.last_mut() {
+                        open.hints.push(broken);
+                        let breakable = match open.kind {
+                            Kind::H => false,
+                            Kind::B => self.text,
+                            _ => true,
+                        };
+                        if !broken && breakable {
+                            self.marks.push(Mark::Kept(open.id, open.nested_newline));
+                        }
+                    }
+                    self.flat.push_str(&" ".repeat(*width));
+                }
+                Event::Open(kind) => {
+                    let open = OpenBox {
+                        kind: *kind,
+                        id: self.opened,
+                        hints: Vec::new(),
+                        nested_newline: false,
+                    };
+                    self.opened += 1;
+                    // A box opened past the maximum indentation may start on
+                    // a new line, which may also be the newline of a later
+                    // hint: try both.
+                    if self.column > self.max_indent {
+                        let mut forced = self.clone();
+                        if let Ok(true) = forced.newline() {
+                            for open in &mut forced.boxes {
+                                open.nested_newline = true;
+                            }
+                            forced.boxes.push(open.clone());
+                            if let Ok(walk) = forced.walk(&events[i + 1..]) {
+                                return Ok(walk);
+                            }
+                        }
+                    }
+                    self.boxes.push(open);
+                }
+                Event::Close => {
+                    let OpenBox {
+                        kind, id, hints, ..
+                    } = self.boxes.pop().unwrap();
+                    let ok = match kind {
+                        Kind::H => hints.iter().all(|b| !b),
+                        Kind::V => hints.iter().all(|b| *b),
+                        Kind::Hv => hints.iter().all(|b| *b) || hints.iter().all(|b| !b),
+                        Kind::Hov | Kind::B => true,
+                    };
+                    if !ok {
+                        return Err(format!("{:?} box broken at {:?}", kind, hints));
+                    }
+                    self.marks.push(Mark::Close(id, self.column));
+                }
+            }
+        }
+        if !self.items.is_empty() {
+            return Err(format!("trailing output {:?}", self.items));
+        }
+        self.end_line()?;
+        Ok(self)
+    }
+}
+
+// Check the document of a seed, returning why its lines past the margin
+// are.
+fn check_seed(seed: u64) -> Result<Vec<Overflow>, String> {
+    let mut rng = Rng::new(seed);
+    let margin = rng.range(8, 60);
+    let max_indent = rng.range(2, margin - 1);
+    let doc = gen_box(&mut rng, 0);
+
+    let mut out = SymbolicOutput::new();
+    let mut f = BufPrinter::with_output(
+        margin as i32,
+        (margin - max_indent) as i32,
+        max_indent as i32,
+        i32::MAX,
+        &mut out,
+    );
+    print(&mut f, &doc);
+    f.print_flush();
+    if (f.left_total, f.right_total, f.curr_depth) != (1, 1, 1) || f.queue.len() != 1 {
+        return Err(format!(
+            "left_total {}, right_total {}, depth {} and {} tokens after the flush",
+            f.left_total,
+            f.right_total,
+            f.curr_depth,
+            f.queue.len()
+        ));
+    }
+    drop(f);
+
+    let items = out.items();
+    let mut doc_events = Vec::new();
+    events(&doc, &mut doc_events);
+    let walk = Items {
+        items: &items[..items.len() - 1],
+        flat: String::new(),
+        column: 0,
+        margin,
+        max_indent,
+        boxes: Vec::new(),
+        opened: 0,
+        text: false,
+        marks: Vec::new(),
+        overflows: Vec::new(),
+    }
+    .walk(&doc_events)?;
+    let mut text = String::new();
+    flat(&doc, &mut text);
+    if walk.flat != text {
+        return Err(format!("flattened to {:?}, not {:?}", walk.flat, text));
+    }
+    Ok(walk.overflows)
+}
+
+#[test]
+fn test_invariants() {
+    let mut overflows = Vec::new();
+    for seed in 0..2000 {
+        match check_seed(seed) {
+            Ok(o) => overflows.extend(o),
+            Err(e) => panic!("seed {}: {}", seed, e),
+        }
+    }
+    for overflow in [
+        Overflow::Unbreakable,
+        Overflow::BoxEnd,
+        Overflow::NestedNewline,
+    ] {
+        assert!(
+            overflows.contains(&overflow),
+            "no line past the margin as {:?}",
+            overflow
+        );
+    }
+}
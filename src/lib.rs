@@ -1,48 +1,46 @@
 // A Rust pretty-printer implementation based on the OCaml stdlib Formatter
-//
-// TODO@@
-//
-// Write tests for formatter
-//
-// Define PP with method fprintf/pprint which takes:
-
-use std::{
-    cmp::min,
-    collections::{HashMap, VecDeque},
-    ops::Add,
-};
-
-// pub trait Pretty {
-//     fn prettify(&self, ppf: &mut BufPrinter<'_>) -> String;
-// }
-
-/*
-* Handling usize -> i32 conversion.
-*/
-trait LenAsI32 {
-    fn len_i32(&self) -> i32;
-}
 
-trait SizeAsI32
-where
-    Self: LenAsI32,
-{
-    fn size_i32(&self) -> i32;
-}
+use std::{borrow::Cow, cmp::min, collections::VecDeque, fmt, ops::Add};
 
-trait CountAsI32 {
-    fn count_i32(&self) -> i32;
-}
+use geometry::validate_geometry;
+use text::Paragraph;
 
-impl LenAsI32 for str {
-    fn len_i32(&self) -> i32 {
-        self.len().try_into().unwrap()
-    }
-}
+// The procedural macros refer to this crate as `::pp`, also from within.
+extern crate self as pp;
+
+mod ansi;
+mod builders;
+mod combinators;
+mod doc;
+mod document;
+mod error;
+mod geometry;
+#[cfg(test)]
+mod invariants;
+mod output;
+mod pretty;
+mod tags;
+mod text;
+mod width;
+mod write;
+
+pub use ansi::{AnsiStyler, Color, Style};
+pub use builders::{PpList, PpMap, PpStruct, PpTuple};
+pub use combinators::{sep_comma, sep_cut, sep_space};
+pub use doc::Doc;
+pub use document::Document;
+pub use error::PpError;
+pub use geometry::{check_geometry, Geometry};
+pub use output::{FmtOutput, FnOutput, IoOutput, Output, OutputItem, SymbolicOutput};
+pub use pp_macros::{pp_printf, Pretty};
+pub use pretty::{pretty_to_string, Pretty};
+pub use tags::{DefaultTagFunctions, Stag, TagFunctions};
+pub use text::Justify;
+pub use width::display_width;
 
 const INFINITY: i32 = 1000000010;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 struct Size(i32);
 
 impl Size {
@@ -66,12 +64,21 @@ impl From<i32> for Size {
         Size(value)
     }
 }
-//
-// Convert from an i32 to Size
-impl From<usize> for Size {
-    fn from(value: usize) -> Self {
-        // probably be careful of overflows etc
-        Size(value as i32)
+
+// Limit a user-supplied size so that it can neither be taken as infinite
+// nor overflow the queue totals.
+fn pp_limit(n: usize) -> i32 {
+    match i32::try_from(n) {
+        Ok(n) if n < INFINITY => n,
+        _ => INFINITY - 1,
+    }
+}
+
+// Convert a user-supplied size, failing where pp_limit would limit it.
+fn pp_checked(n: usize) -> Result<i32, PpError> {
+    match i32::try_from(n) {
+        Ok(n) if n < INFINITY => Ok(n),
+        _ => Err(PpError::Overflow),
     }
 }
 
@@ -103,7 +110,7 @@ impl Add<i32> for Size {
 *   (the box behaves as an horizontal or vertical box but break hints split
 *      the line if splitting would move to the left)
 */
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum PpBox {
     Hbox(),
     Vbox(),
@@ -113,69 +120,74 @@ pub enum PpBox {
     Fits(),
 }
 
-#[derive(Clone, Debug)]
-enum PpToken {
-    Text(String), // normal text
-    Break {
-        fits: (String, i32, String),   // line is not split
-        breaks: (String, i32, String), // line is split
-    },
-    Begin(i32, PpBox), // Beginning of a box
-    End(),             // End of a box
-    Newline(),         // Force a newline inside a box
-                       // OpenTag(), // opening a tag name
-                       // CloseTag(), // closing the most recently opened tab
-}
+// The strings and spaces of a custom break, as given to print_custom_break.
+type CustomBreak = ((String, i32, String), (String, i32, String));
 
-impl LenAsI32 for PpToken {
-    fn len_i32(&self) -> i32 {
-        match self {
-            PpToken::Text(s) => s.len_i32(),
-            PpToken::Break { fits, breaks } => 0 as i32,
-            PpToken::Begin(_, _) => 0 as i32,
-            PpToken::End() => 0 as i32,
-            PpToken::Newline() => 0 as i32,
-        }
-    }
-}
-
-impl PpToken {
-    fn len(&self) -> usize {
-        match self {
-            PpToken::Text(s) => s.len(),
-            PpToken::Break { fits, breaks } => 0,
-            PpToken::Begin(_, _) => 0,
-            PpToken::End() => 0,
-            PpToken::Newline() => 0,
-        }
-    }
+/* The tokens borrow their text when it lives as long as the printer, and
+* keep plain break hints, by far the most common ones, down to two integers:
+* only custom breaks carry strings, boxed away from the queue. */
+#[derive(Clone, Debug)]
+enum PpToken<'a> {
+    Text(Cow<'a, str>),            // normal text
+    Break(i32, i32),               // A break hint: width if the line is not split, else offset
+    CustomBreak(Box<CustomBreak>), // A break hint with strings: (fits, breaks)
+    Begin(i32, PpBox),             // Beginning of a box
+    End(),                         // End of a box
+    Newline(),                     // Force a newline inside a box
+    IfNewline(),                   // Print the next token only if the line was just split
+    OpenTag(Stag),                 // Opening a tag name
+    CloseTag(),                    // Closing the most recently opened tag
+    TBegin(),                      // Beginning of a tabulation box
+    TEnd(),                        // End of a tabulation box
+    SetTab(),                      // Set a tabulation stop at the current position
+    TBreak(i32, i32),              // Go to the next tabulation stop
+    Paragraph(Box<Paragraph>),     // Words filling and aligned on the lines
 }
 
-impl SizeAsI32 for PpToken {
-    fn size_i32(&self) -> i32 {
+impl PpToken<'_> {
+    // A copy of the token owning its text.
+    fn to_owned(&self) -> PpToken<'static> {
         match self {
-            PpToken::Text(s) => s.len_i32(),
-            PpToken::Break { fits, breaks } => 0,
-            PpToken::Begin(_, _) => 0,
-            PpToken::End() => 0,
-            PpToken::Newline() => 0,
+            PpToken::Text(s) => PpToken::Text(Cow::Owned(s.to_string())),
+            PpToken::Break(width, offset) => PpToken::Break(*width, *offset),
+            PpToken::CustomBreak(custom) => PpToken::CustomBreak(custom.clone()),
+            PpToken::Begin(indent, ty) => PpToken::Begin(*indent, *ty),
+            PpToken::End() => PpToken::End(),
+            PpToken::Newline() => PpToken::Newline(),
+            PpToken::IfNewline() => PpToken::IfNewline(),
+            PpToken::OpenTag(tag) => PpToken::OpenTag(tag.clone()),
+            PpToken::CloseTag() => PpToken::CloseTag(),
+            PpToken::TBegin() => PpToken::TBegin(),
+            PpToken::TEnd() => PpToken::TEnd(),
+            PpToken::SetTab() => PpToken::SetTab(),
+            PpToken::TBreak(width, offset) => PpToken::TBreak(*width, *offset),
+            PpToken::Paragraph(paragraph) => PpToken::Paragraph(paragraph.clone()),
         }
     }
 }
 
 // The pretty-printer queue:
 
-// PpQueueT contains a usize which is the token_id reference for self.tokens
-#[derive(Clone, Debug)]
-pub struct PpQueueT(usize);
+// A queue element owns its token, together with its size (negative until
+// known) and its length. It is dropped once formatted.
+#[derive(Debug)]
+struct PpQueueElem<'a> {
+    size: Size,
+    token: PpToken<'a>,
+    length: i32,
+}
 
 /* The pretty-printer scanning stack */
 
 /* The pretty-printer scanning stack: scanning element definition */
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct PpScanT {
     left_total: i32, // Value of self.left_total when the element was enqueued.
-    token_idx: usize,
+    // Position of the element in the queue, counted from the first element
+    // ever enqueued.
+    queue_idx: usize,
+    // Whether the element is a break, rather than the beginning of a box.
+    is_break: bool,
 }
 
 /* The pretty-printer formatting stack:
@@ -187,27 +199,26 @@ while printing tokens. */
 Each stack element describes a pretty-printing box. */
 // PpFormaT is the type of the format stack. usize is the width of the box
 // when loading onto the formatting stack. This is never mutated.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 struct PpFormatT {
     box_type: PpBox,
     box_size: i32,
 }
 const ELLIPSIS: &str = ".";
 
-#[derive(Debug)]
-pub struct BufPrinter {
-    // The token mapping.
-    tokens: Vec<PpToken>,
-    // The token length mapping.
-    token_lengths: HashMap<usize, i32>,
-    // The token size mapping.
-    token_sizes: HashMap<usize, Size>,
+pub struct BufPrinter<'a> {
     // The pretty-printer scanning stack.
     scan_stack: Vec<PpScanT>,
     // The pretty-printer formatting stack.
     format_stack: Vec<PpFormatT>,
-    // The pretty-printer queue.
-    queue: VecDeque<PpQueueT>,
+    // The tabulation stops of the currently open tabulation boxes, each
+    // sorted in increasing order.
+    tbox_stack: Vec<Vec<i32>>,
+    // The pretty-printer queue, holding the tokens not yet formatted.
+    queue: VecDeque<PpQueueElem<'a>>,
+    // Position of the front of the queue, counted from the first element
+    // ever enqueued.
+    queue_head: usize,
     // Value of the right margin.
     margin: i32,
     // Minimum space left before margin, when opening a box.
@@ -231,50 +242,120 @@ pub struct BufPrinter {
     max_boxes: i32,
     // Ellipsis string.
     // ellipsis: String,
-    // Output function
-    // out_string: fn(String, i32, i32) -> (),
-    // Flushing function
-    // out_flush: fn(),
-    // Output of new lines
-    // out_newline: fn(),
-    // Output of break hints spaces.
-    // out_spaces: fn(),
-    // The output buffer we write to for now.
-    pub out_buf: String,
+    // The stack of tags opened for printing.
+    tag_stack: Vec<Stag>,
+    // The stack of tags whose opening marker has been output.
+    mark_stack: Vec<Stag>,
+    // Call the tag printing functions.
+    print_tags: bool,
+    // Output the tag markers.
+    mark_tags: bool,
+    // Turn the spaces of text written through fmt::Write into break hints.
+    write_breaks: bool,
+    // The tokens enqueued since recording started, with their lengths.
+    recording: Option<Vec<(PpToken<'static>, i32)>>,
+    // Only record the tokens, without laying them out.
+    record_only: bool,
+    // The tag-handling functions.
+    tag_functions: Box<dyn TagFunctions + 'a>,
+    // The function measuring the display width of text.
+    width: Box<dyn Fn(&str) -> usize + 'a>,
+    // The output device: strings, newlines, spaces, indentation and
+    // flushing all go through it.
+    out: Box<dyn Output + 'a>,
+}
+
+impl fmt::Debug for BufPrinter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufPrinter")
+            .field("scan_stack", &self.scan_stack)
+            .field("format_stack", &self.format_stack)
+            .field("tbox_stack", &self.tbox_stack)
+            .field("queue", &self.queue)
+            .field("queue_head", &self.queue_head)
+            .field("margin", &self.margin)
+            .field("min_space_left", &self.min_space_left)
+            .field("max_indent", &self.max_indent)
+            .field("space_left", &self.space_left)
+            .field("current_indent", &self.current_indent)
+            .field("is_new_line", &self.is_new_line)
+            .field("left_total", &self.left_total)
+            .field("right_total", &self.right_total)
+            .field("curr_depth", &self.curr_depth)
+            .field("max_boxes", &self.max_boxes)
+            .field("tag_stack", &self.tag_stack)
+            .field("mark_stack", &self.mark_stack)
+            .field("print_tags", &self.print_tags)
+            .field("mark_tags", &self.mark_tags)
+            .field("write_breaks", &self.write_breaks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufPrinter<'static> {
+    // A pretty-printer writing to an in-memory string buffer, see
+    // `contents` and `flush_str`.
+    pub fn new(margin: i32, min_space_left: i32, max_indent: i32, max_boxes: i32) -> Self {
+        BufPrinter::with_output(margin, min_space_left, max_indent, max_boxes, String::new())
+    }
 }
 
-impl BufPrinter {
-    pub fn new(
+impl<'a> BufPrinter<'a> {
+    // A pretty-printer writing to the given output device. As in OCaml,
+    // the geometry starts as the default one and the margin, maximum
+    // indentation and maximum number of boxes are then set with
+    // `set_margin`, `set_max_indent` and `set_max_boxes`, which ignore or
+    // limit out of range values; the minimum space left follows from the
+    // margin and maximum indentation. `with_geometry` is the constructor
+    // failing on such values.
+    pub fn with_output<O: Output + 'a>(
         margin: i32,
-        min_space_left: i32,
+        _min_space_left: i32,
         max_indent: i32,
         max_boxes: i32,
-        // out_string: fn(String, i32, i32) -> (),
-        // out_flush: fn(),
-        // out_newline: fn(),
-        // out_spaces: fn(),
+        out: O,
     ) -> Self {
-        let tokens: Vec<PpToken> = Vec::new();
-        // Token length mapping.
-        // let token_lengths: Vec<i32> = Vec::new();
-        let token_lengths: HashMap<usize, i32> = HashMap::new();
-        // Token size mapping.
-        // let token_sizes: Vec<Size> = Vec::new();
-        let token_sizes: HashMap<usize, Size> = HashMap::new();
+        let mut f = BufPrinter::printer(&Geometry::default(), i32::MAX, out);
+        f.set_margin(usize::try_from(margin).unwrap_or(0));
+        f.set_max_indent(usize::try_from(max_indent).unwrap_or(0));
+        f.set_max_boxes(usize::try_from(max_boxes).unwrap_or(0));
+        f
+    }
+
+    // A pretty-printer of the given geometry writing to the given output
+    // device, failing if the geometry does not pass `check_geometry` or
+    // allows less than two boxes.
+    pub fn with_geometry<O: Output + 'a>(
+        geometry: &Geometry,
+        max_boxes: i32,
+        out: O,
+    ) -> anyhow::Result<Self> {
+        if let Err(e) = validate_geometry(geometry) {
+            anyhow::bail!("with_geometry: {}", e)
+        } else if max_boxes < 2 {
+            anyhow::bail!("with_geometry: max_boxes < 2")
+        }
+        Ok(BufPrinter::printer(geometry, max_boxes, out))
+    }
+
+    fn printer<O: Output + 'a>(geometry: &Geometry, max_boxes: i32, out: O) -> Self {
+        // The geometry is below the infinity, so within an i32.
+        let margin = geometry.margin as i32;
+        let max_indent = geometry.max_indent as i32;
+        let min_space_left = margin - max_indent;
         // The pretty-printer scanning stack.
         let scan_stack: Vec<PpScanT> = Vec::new();
         // The pretty-printer formatting stack.
         let format_stack: Vec<PpFormatT> = Vec::new();
         // The pretty-printer queue.
-        let queue: VecDeque<PpQueueT> = VecDeque::new();
+        let queue: VecDeque<PpQueueElem<'a>> = VecDeque::new();
 
         let mut f = BufPrinter {
-            tokens,
-            token_lengths,
-            token_sizes,
             scan_stack,
             format_stack,
+            tbox_stack: Vec::new(),
             queue,
+            queue_head: 0,
             margin,
             min_space_left,
             max_indent,
@@ -285,63 +366,125 @@ impl BufPrinter {
             right_total: 1,
             curr_depth: 1,
             max_boxes,
-            // out_string,
-            // out_flush,
-            // out_newline,
-            // out_spaces,
-            out_buf: String::new(),
+            tag_stack: Vec::new(),
+            mark_stack: Vec::new(),
+            print_tags: false,
+            mark_tags: false,
+            write_breaks: false,
+            recording: None,
+            record_only: false,
+            tag_functions: Box::new(DefaultTagFunctions),
+            width: Box::new(display_width),
+            out: Box::new(out),
         };
-        let sys_tok = PpToken::Begin(0, PpBox::Hovbox());
-        let token_idx = f.add_token(sys_tok);
-        // f.tokens.push(sys_tok);
-        f.token_lengths.insert(token_idx, 0);
-        f.token_sizes.insert(token_idx, Size::UNKNOWN);
-        f.queue.push_back(PpQueueT(token_idx));
+        let sys_tok = PpQueueElem {
+            size: Size::UNKNOWN,
+            token: PpToken::Begin(0, PpBox::Hovbox()),
+            length: 0,
+        };
+        f.queue.push_back(sys_tok);
 
         f.initialise_scan_stack();
         f.scan_stack.push(PpScanT {
-            token_idx,
             left_total: 1,
+            queue_idx: 0,
+            is_break: false,
         });
         f
     }
 
-    fn add_token(&mut self, token: PpToken) -> usize {
-        self.tokens.push(token);
-        return self.tokens.len() - 1;
+    // Enqueue a token, failing if the total width of the material overflows.
+    // Returns the position of the token in the queue.
+    fn enqueue(&mut self, elem: PpQueueElem<'a>) -> Result<usize, PpError> {
+        if let Some(recording) = &mut self.recording {
+            // Tags are recorded when opened and closed, marked or not.
+            if !matches!(elem.token, PpToken::OpenTag(_) | PpToken::CloseTag()) {
+                recording.push((elem.token.to_owned(), elem.length));
+            }
+        }
+        if self.record_only {
+            return Ok(self.queue_head + self.queue.len());
+        }
+        self.right_total = self
+            .right_total
+            .checked_add(elem.length)
+            .ok_or(PpError::Overflow)?;
+        self.queue.push_back(elem);
+        Ok(self.queue_head + self.queue.len() - 1)
     }
 
-    fn enqueue(&mut self, token_id: usize) {
-        self.right_total += self.token_lengths[&token_id];
-        self.queue.push_back(PpQueueT(token_id))
+    // Take the token at the front of the queue.
+    fn dequeue(&mut self) -> Option<PpQueueElem<'a>> {
+        let elem = self.queue.pop_front()?;
+        self.queue_head += 1;
+        Some(elem)
     }
 
     fn clear_queue(&mut self) {
         self.left_total = 1;
         self.right_total = 1;
+        self.queue_head += self.queue.len();
         self.queue.clear()
     }
 
     fn output_string(&mut self, s: &str) {
-        self.out_buf.push_str(s);
+        self.out.output_string(s);
     }
 
     pub fn output_newline(&mut self) {
-        self.out_buf.push_str("\n");
+        self.out.output_newline();
     }
 
     fn output_spaces(&mut self, n: i32) {
-        for _ in 0..n {
-            self.out_buf.push_str(" ");
+        if n > 0 {
+            self.out.output_spaces(n as usize);
         }
     }
 
     pub fn output_indent(&mut self, n: i32) {
-        for _ in 0..n {
-            self.out_buf.push_str(" ");
+        if n > 0 {
+            self.out.output_indent(n as usize);
         }
     }
 
+    // The display width of a string, as measured by the width function.
+    fn width_i32(&self, s: &str) -> Result<i32, PpError> {
+        pp_checked((self.width)(s))
+    }
+
+    // The display width of a string, limited below the infinity.
+    fn width_limited(&self, s: &str) -> i32 {
+        pp_limit((self.width)(s))
+    }
+
+    // Replace the function measuring the display width of text, which is
+    // `display_width` by default.
+    pub fn set_width_function<F: Fn(&str) -> usize + 'a>(&mut self, width: F) {
+        self.width = Box::new(width);
+    }
+
+    fn output_flush(&mut self) {
+        self.out.flush();
+    }
+
+    // Replace the output device, returning the previous one.
+    pub fn set_output<O: Output + 'a>(&mut self, out: O) -> Box<dyn Output + 'a> {
+        std::mem::replace(&mut self.out, Box::new(out))
+    }
+
+    // The text written so far, when the output device keeps it in memory
+    // (the default string buffer does); empty otherwise.
+    pub fn contents(&self) -> &str {
+        self.out.contents().unwrap_or("")
+    }
+
+    // Flush the pretty-printer and take the text accumulated by the output
+    // device, as OCaml's `flush_str_formatter`.
+    pub fn flush_str(&mut self) -> String {
+        self.print_flush();
+        self.out.take_contents().unwrap_or_default()
+    }
+
     fn format_pp_text(&mut self, s: &str, size: i32) {
         self.space_left -= size;
         self.output_string(s);
@@ -349,22 +492,33 @@ impl BufPrinter {
     }
 
     fn format_string(&mut self, s: &str) {
-        if s != "" {
-            self.format_pp_text(s, s.len_i32())
+        if !s.is_empty() {
+            self.format_pp_text(s, self.width_limited(s))
         }
     }
 
     // To format a break, indenting a new line.
     fn break_new_line(&mut self, before: &str, offset: i32, after: &str, width: i32) {
         self.format_string(before);
+        // Tag markers may need to be suspended around the newline and the
+        // indentation, for instance so that styles do not bleed into it.
+        let marking = self.mark_tags && !self.mark_stack.is_empty();
+        if marking {
+            let marker = self.tag_functions.mark_line_end();
+            self.output_string(&marker);
+        }
         self.output_newline();
         self.is_new_line = true;
-        let indent = self.margin - width + offset;
+        let indent = self.margin.saturating_sub(width).saturating_add(offset);
         // Don't indent more than max_indent.
         let real_indent = min(self.max_indent, indent);
         self.current_indent = real_indent;
         self.space_left = self.margin - self.current_indent;
         self.output_indent(self.current_indent);
+        if marking {
+            let marker = self.tag_functions.mark_line_start();
+            self.output_string(&marker);
+        }
         self.format_string(after);
     }
 
@@ -397,12 +551,9 @@ impl BufPrinter {
     }
 
     fn skip_token(&mut self) {
-        match self.queue.pop_front() {
-            None => (),
-            Some(queue_elem) => {
-                self.left_total -= self.token_lengths[&queue_elem.0];
-                self.space_left += i32::from(&self.token_sizes[&queue_elem.0]);
-            }
+        if let Some(queue_elem) = self.dequeue() {
+            self.left_total -= queue_elem.length;
+            self.space_left += i32::from(&queue_elem.size);
         }
     }
 
@@ -411,10 +562,7 @@ impl BufPrinter {
      * */
 
     // Formatting a token with a given size.
-    fn format_pp_token(&mut self, token_id: usize) {
-        let size = self.token_sizes[&token_id].clone();
-        // Do we need to clone always?
-        let token = &self.tokens[token_id].clone();
+    fn format_pp_token(&mut self, size: Size, token: &PpToken<'_>) {
         match token {
             PpToken::Text(s) => self.format_pp_text(s, i32::from(&size)),
 
@@ -422,19 +570,18 @@ impl BufPrinter {
                 let insertion_point = self.margin - self.space_left;
                 if insertion_point > self.max_indent {
                     // can not open a box right there.
-                    // this requires mut ref hence the clone.
                     self.force_break_line()
                 }
-                let width = self.space_left - off;
+                let width = self.space_left.saturating_sub(*off);
                 let new_box_t = match box_t {
-                    PpBox::Vbox() => box_t.clone(),
+                    PpBox::Vbox() => *box_t,
                     PpBox::Hbox()
                     | PpBox::Hvbox()
                     | PpBox::Hovbox()
                     | PpBox::Box()
                     | PpBox::Fits() => {
                         if i32::from(&size) > self.space_left {
-                            box_t.clone()
+                            *box_t
                         } else {
                             PpBox::Fits()
                         }
@@ -450,99 +597,164 @@ impl BufPrinter {
                 let _ = self.format_stack.pop();
             }
 
-            PpToken::Newline() => match self.format_stack.pop() {
+            PpToken::TBegin() => self.tbox_stack.push(Vec::new()),
+
+            PpToken::TEnd() => {
+                let _ = self.tbox_stack.pop();
+            }
+
+            PpToken::SetTab() => {
+                let insertion_point = self.margin - self.space_left;
+                if let Some(tabs) = self.tbox_stack.last_mut() {
+                    let pos = tabs.partition_point(|tab| *tab <= insertion_point);
+                    tabs.insert(pos, insertion_point);
+                }
+            }
+
+            PpToken::TBreak(n, off) => {
+                let insertion_point = self.margin - self.space_left;
+                if let Some(tabs) = self.tbox_stack.last() {
+                    // The first tabulation stop at or after the insertion
+                    // point, or the first one of the box to go to a new line.
+                    let tab = match tabs.iter().find(|tab| **tab >= insertion_point) {
+                        Some(tab) => *tab,
+                        None => tabs.first().copied().unwrap_or(insertion_point),
+                    };
+                    let offset = tab - insertion_point;
+                    if offset >= 0 {
+                        self.break_same_line("", offset + n, "");
+                    } else {
+                        self.break_new_line("", tab + off, "", self.margin);
+                    }
+                }
+            }
+
+            PpToken::OpenTag(tag) => {
+                let marker = self.tag_functions.mark_open(tag);
+                self.output_string(&marker);
+                self.mark_stack.push(tag.clone());
+            }
+
+            PpToken::CloseTag() => match self.mark_stack.pop() {
+                None => (),
+                Some(tag) => {
+                    let marker = self.tag_functions.mark_close(&tag);
+                    self.output_string(&marker);
+                }
+            },
+
+            PpToken::Newline() => match self.format_stack.last() {
                 None => self.output_newline(),
                 Some(PpFormatT {
                     box_type: _,
                     box_size: width,
-                }) => self.break_line(width),
+                }) => self.break_line(*width),
             },
 
-            PpToken::Break { fits, breaks } => {
-                let (before, off, _) = breaks;
-                match self.format_stack.last() {
-                    None => (),
-                    Some(PpFormatT {
-                        box_type: box_t,
-                        box_size: width,
-                    }) => match box_t {
-                        PpBox::Hovbox() => {
-                            let size_i32 = i32::from(&size);
-                            let before_i32 = before.len_i32();
-                            if size_i32 + before_i32 > self.space_left {
-                                self.break_new_line(&breaks.0, breaks.1, &breaks.2, *width);
-                            } else {
-                                self.break_same_line(&fits.0, fits.1, &fits.2)
-                            }
-                        }
-                        PpBox::Box() => {
-                            // Has the line just been broken here?
-                            if self.is_new_line {
-                                self.break_same_line(&fits.0, fits.1, &fits.2)
-                            } else {
-                                let size_i32 = i32::from(&size);
-                                let before_i32 = before.len_i32();
-                                if size_i32 + before_i32 > self.space_left {
-                                    self.break_new_line(&breaks.0, breaks.1, &breaks.2, *width);
-                                } else {
-                                    if self.current_indent > self.margin - width + off {
-                                        self.break_new_line(&breaks.0, breaks.1, &breaks.2, *width);
-                                    } else {
-                                        self.break_same_line(&fits.0, fits.1, &fits.2)
-                                    }
-                                }
-                            }
-                        }
-                        PpBox::Hbox() => {
-                            self.break_new_line(&breaks.0, breaks.1, &breaks.2, *width)
-                        }
-                        PpBox::Hvbox() => {
-                            self.break_new_line(&breaks.0, breaks.1, &breaks.2, *width)
-                        }
-                        PpBox::Vbox() => self.break_same_line(&fits.0, fits.1, &fits.2),
-                        PpBox::Fits() => self.break_same_line(&fits.0, fits.1, &fits.2),
-                    },
+            PpToken::IfNewline() => {
+                if !self.is_new_line {
+                    self.skip_token()
+                }
+            }
+
+            PpToken::Break(width, offset) => {
+                self.format_break(size, ("", *width, ""), ("", *offset, ""))
+            }
+
+            PpToken::CustomBreak(custom) => {
+                let (fits, breaks) = &**custom;
+                self.format_break(
+                    size,
+                    (&fits.0, fits.1, &fits.2),
+                    (&breaks.0, breaks.1, &breaks.2),
+                )
+            }
+
+            PpToken::Paragraph(paragraph) => self.format_paragraph(paragraph),
+        }
+    }
+
+    // Formatting a break hint with a given size, according to the
+    // enclosing box.
+    fn format_break(&mut self, size: Size, fits: (&str, i32, &str), breaks: (&str, i32, &str)) {
+        let Some(&PpFormatT {
+            box_type,
+            box_size: width,
+        }) = self.format_stack.last()
+        else {
+            return;
+        };
+        let (before, off, _) = breaks;
+        match box_type {
+            PpBox::Hovbox() => {
+                let before_i32 = self.width_limited(before);
+                if size.0.saturating_add(before_i32) > self.space_left {
+                    self.break_new_line(breaks.0, breaks.1, breaks.2, width);
+                } else {
+                    self.break_same_line(fits.0, fits.1, fits.2)
+                }
+            }
+            PpBox::Box() => {
+                // Has the line just been broken here?
+                if self.is_new_line {
+                    self.break_same_line(fits.0, fits.1, fits.2)
+                } else {
+                    let before_i32 = self.width_limited(before);
+                    if size.0.saturating_add(before_i32) > self.space_left
+                        || self.current_indent
+                            > self.margin.saturating_sub(width).saturating_add(off)
+                    {
+                        self.break_new_line(breaks.0, breaks.1, breaks.2, width);
+                    } else {
+                        self.break_same_line(fits.0, fits.1, fits.2)
+                    }
                 }
             }
+            PpBox::Vbox() | PpBox::Hvbox() => {
+                self.break_new_line(breaks.0, breaks.1, breaks.2, width)
+            }
+            PpBox::Hbox() | PpBox::Fits() => self.break_same_line(fits.0, fits.1, fits.2),
         }
     }
 
+    // Format the tokens at the front of the queue whose size is known, or
+    // which can no longer fit on the line. Formatted tokens are dropped.
     fn advance_left(&mut self) {
-        let queue_elem = self.queue.front();
-        match queue_elem {
-            None => (),
-            Some(queue_elem) => {
-                let pending_count = self.right_total - self.left_total;
-                if self.token_sizes[&queue_elem.0].is_known() || pending_count >= self.space_left {
-                    let token_id = queue_elem.0;
-                    let _ = self.queue.pop_front();
-                    self.token_sizes.entry(token_id).and_modify(|size| {
-                        if !size.is_known() {
-                            *size = Size(INFINITY)
-                        }
-                    });
-                    self.format_pp_token(token_id);
-                    // self.token_sizes[token_id] = original_size;
-                    self.left_total += self.token_lengths[&token_id];
-                    // TODO@ make recursive as there's not tail recursion optimisation
-                    self.advance_left();
-                }
+        while let Some(queue_elem) = self.queue.front() {
+            let pending_count = self.right_total - self.left_total;
+            if !queue_elem.size.is_known() && pending_count < self.space_left {
+                break;
             }
+            let Some(PpQueueElem {
+                size,
+                token,
+                length,
+            }) = self.dequeue()
+            else {
+                break;
+            };
+            let size = if size.is_known() {
+                size
+            } else {
+                Size(INFINITY)
+            };
+            self.format_pp_token(size, &token);
+            self.left_total += length;
         }
     }
 
-    fn enqueue_advance(&mut self, token_id: usize) {
-        self.enqueue(token_id);
-        self.advance_left()
+    fn enqueue_advance(&mut self, elem: PpQueueElem<'a>) -> Result<(), PpError> {
+        self.enqueue(elem)?;
+        self.advance_left();
+        Ok(())
     }
 
-    fn enqueue_string_as(&mut self, s: String, size: i32) {
-        let token = PpToken::Text(s);
-        // Move ownership
-        let token_idx = self.add_token(token);
-        self.token_sizes.insert(token_idx, Size(size));
-        self.token_lengths.insert(token_idx, size);
-        self.enqueue_advance(token_idx)
+    fn enqueue_string_as(&mut self, s: Cow<'a, str>, size: i32) -> Result<(), PpError> {
+        self.enqueue_advance(PpQueueElem {
+            size: Size(size),
+            token: PpToken::Text(s),
+            length: size,
+        })
     }
 
     // fn enqueue_string(&mut self, s: String) {
@@ -554,18 +766,15 @@ impl BufPrinter {
      * determine size of boxes.
      * */
 
-    // The scan_stack is never empty.
+    // The scan_stack is never empty: its bottom is a sentinel, which is
+    // always older than the tokens already printed, and so never refers to
+    // the queue.
     fn initialise_scan_stack(&mut self) {
         self.scan_stack.clear();
-        let sentinel_token = PpToken::Text("".to_string());
-
-        let token_idx = self.add_token(sentinel_token);
-        self.token_sizes.insert(token_idx, Size::UNKNOWN);
-        self.token_lengths.insert(token_idx, 0);
-
         self.scan_stack.push(PpScanT {
-            token_idx,
             left_total: -1,
+            queue_idx: usize::MAX,
+            is_break: false,
         })
     }
 
@@ -576,205 +785,419 @@ impl BufPrinter {
      * in each case scan_stack is popped.
      *
      * Note:
-     * Pattern matching on scan stack is exhaustive, since scan_stack is never
-     * empty.
-     * Pattern matching on token in scan stack is also exhaustive,
-     * since scan_push is used on breaks and opening of boxes.
+     * Only breaks and openings of boxes are pushed on the scan stack, and
+     * scan_stack is never empty.
+     * A token may have been printed while still on the scan stack, its size
+     * is then no longer needed.
      */
-    fn set_size(&mut self, ty: bool) {
-        match self.scan_stack.last() {
-            None => (),
-            Some(PpScanT {
-                left_total,
-                token_idx: token_id,
-            }) => {
-                if *left_total < self.left_total {
-                    self.initialise_scan_stack();
-                    return;
-                }
-                let token = &self.tokens[*token_id];
-                match token {
-                    PpToken::Break { fits: _, breaks: _ } => {
-                        if ty {
-                            self.token_sizes
-                                .entry(*token_id)
-                                .and_modify(|size| size.0 += self.right_total);
-
-                            let _ = self.scan_stack.pop();
-                        }
-                    }
-                    PpToken::Begin(_, _) => {
-                        if !ty {
-                            self.token_sizes
-                                .entry(*token_id)
-                                .and_modify(|size| size.0 += self.right_total);
-
-                            let _ = self.scan_stack.pop();
-                        }
-                    }
-                    PpToken::Text(_) | PpToken::End() | PpToken::Newline() => (),
-                }
+    fn set_size(&mut self, ty: bool) -> Result<(), PpError> {
+        if self.record_only {
+            return Ok(());
+        }
+        let Some(&PpScanT {
+            left_total,
+            queue_idx,
+            is_break,
+        }) = self.scan_stack.last()
+        else {
+            return Err(PpError::Invariant("empty scan stack"));
+        };
+        if left_total < self.left_total {
+            self.initialise_scan_stack();
+            return Ok(());
+        }
+        if is_break == ty {
+            if let Some(pos) = queue_idx.checked_sub(self.queue_head) {
+                let elem = self
+                    .queue
+                    .get_mut(pos)
+                    .ok_or(PpError::Invariant("scanned token not in the queue"))?;
+                elem.size.0 += self.right_total;
             }
+            let _ = self.scan_stack.pop();
         }
+        Ok(())
     }
 
-    fn scan_push(&mut self, b: bool, token_id: usize) {
-        self.enqueue(token_id);
+    fn scan_push(&mut self, b: bool, elem: PpQueueElem<'a>) -> Result<(), PpError> {
+        let queue_idx = self.enqueue(elem)?;
+        if self.record_only {
+            return Ok(());
+        }
         if b {
-            self.set_size(true);
+            self.set_size(true)?;
         }
         let scan_elem = PpScanT {
             left_total: self.right_total,
-            token_idx: token_id,
+            queue_idx,
+            is_break: b,
         };
-        self.scan_stack.push(scan_elem)
+        self.scan_stack.push(scan_elem);
+        Ok(())
     }
 
-    fn open_box_gen(&mut self, indent: i32, br_ty: PpBox) {
+    fn open_box_gen(&mut self, indent: i32, br_ty: PpBox) -> Result<(), PpError> {
         self.curr_depth += 1;
         if self.curr_depth < self.max_boxes {
-            let size = -1 * self.right_total;
-            let token = PpToken::Begin(indent, br_ty);
-
-            let token_idx = self.add_token(token);
-            self.token_sizes.insert(token_idx, Size(size));
-            self.token_lengths.insert(token_idx, 0);
-            self.scan_push(false, token_idx);
+            let elem = PpQueueElem {
+                size: Size(-self.right_total),
+                token: PpToken::Begin(indent, br_ty),
+                length: 0,
+            };
+            self.scan_push(false, elem)
         } else if self.curr_depth == self.max_boxes {
-            self.enqueue_string_as(ELLIPSIS.to_string(), 1);
-        };
+            self.enqueue_string_as(Cow::Borrowed(ELLIPSIS), 1)
+        } else {
+            Ok(())
+        }
     }
 
     fn open_sys_box(&mut self) {
-        self.open_box_gen(0, PpBox::Hbox())
+        // The system box is opened on an empty queue, so it cannot fail. It
+        // is a hovbox, as the one the printer starts with, and is not part
+        // of the material, so not recorded.
+        let recording = self.recording.take();
+        let _ = self.open_box_gen(0, PpBox::Hovbox());
+        self.recording = recording;
     }
 
     pub fn close_box(&mut self) {
-        if self.curr_depth > 1 {
-            if self.curr_depth < self.max_boxes {
-                let token = PpToken::End();
-
-                let token_idx = self.add_token(token);
-                self.token_sizes.insert(token_idx, Size::ZERO);
-                self.token_lengths.insert(token_idx, 0);
+        let _ = self.try_close_box();
+    }
 
-                self.enqueue(token_idx);
-                self.set_size(false);
-                self.set_size(true);
-            }
-            self.curr_depth -= 1;
+    // Close the most recently opened box, failing if there is none.
+    pub fn try_close_box(&mut self) -> Result<(), PpError> {
+        if self.curr_depth <= 1 {
+            return Err(PpError::NoOpenBox);
+        }
+        let mut result = Ok(());
+        if self.curr_depth < self.max_boxes {
+            let elem = PpQueueElem {
+                size: Size::ZERO,
+                token: PpToken::End(),
+                length: 0,
+            };
+
+            // Size the last break of the box first, then the box itself.
+            result = self
+                .enqueue(elem)
+                .and_then(|_| self.set_size(true))
+                .and_then(|()| self.set_size(false));
         }
+        self.curr_depth -= 1;
+        result
     }
 
     fn rinit(&mut self) {
         self.clear_queue();
         self.initialise_scan_stack();
         self.format_stack.clear();
+        self.tbox_stack.clear();
+        self.tag_stack.clear();
+        self.close_marks();
         self.current_indent = 0;
         self.curr_depth = 0;
         self.space_left = self.margin;
         self.open_sys_box();
     }
 
-    fn flush_queue(&mut self, end_with_newline: bool) {
+    // Close the open boxes and output the pending material. The
+    // pretty-printer is reinitialised even if this fails.
+    fn flush_queue(&mut self, end_with_newline: bool) -> Result<(), PpError> {
+        self.clear_tag_stack();
+        let mut result = Ok(());
         while self.curr_depth > 1 {
-            self.close_box();
+            result = result.and(self.try_close_box());
         }
-        self.right_total = INFINITY;
+        // Pending sizes are taken as infinite, hence all tokens output.
+        self.right_total = self.right_total.max(INFINITY);
         self.advance_left();
+        self.close_marks();
         if end_with_newline {
+            if let Some(recording) = &mut self.recording {
+                recording.push((PpToken::Newline(), 0));
+            }
             self.output_newline();
         }
         self.rinit();
+        result
+    }
+
+    /*
+     * Semantic tags.
+     * */
+
+    // Open a tag: call the tag printing function and enqueue the opening
+    // marker, according to print_tags and mark_tags. Tags take no room in
+    // the layout.
+    pub fn open_stag<T: Into<Stag>>(&mut self, tag: T) {
+        let tag = tag.into();
+        if let Some(recording) = &mut self.recording {
+            recording.push((PpToken::OpenTag(tag.clone()), 0));
+        }
+        if self.print_tags {
+            self.tag_functions.print_open(&tag);
+            self.tag_stack.push(tag.clone());
+        }
+        if self.mark_tags {
+            let _ = self.enqueue_zero(PpToken::OpenTag(tag));
+        }
+    }
+
+    // Close the most recently opened tag.
+    pub fn close_stag(&mut self) {
+        if let Some(recording) = &mut self.recording {
+            recording.push((PpToken::CloseTag(), 0));
+        }
+        if self.mark_tags {
+            let _ = self.enqueue_zero(PpToken::CloseTag());
+        }
+        if self.print_tags {
+            if let Some(tag) = self.tag_stack.pop() {
+                self.tag_functions.print_close(&tag);
+            }
+        }
+    }
+
+    // Close the tags still opened for printing.
+    fn clear_tag_stack(&mut self) {
+        for _ in 0..self.tag_stack.len() {
+            self.close_stag();
+        }
+    }
+
+    // Output the closing markers of the tags still marked open, innermost
+    // first, so that the marking functions see every tag closed.
+    fn close_marks(&mut self) {
+        while let Some(tag) = self.mark_stack.pop() {
+            let marker = self.tag_functions.mark_close(&tag);
+            self.output_string(&marker);
+        }
+    }
+
+    // Enqueue a token of size and length zero.
+    fn enqueue_zero(&mut self, token: PpToken<'a>) -> Result<(), PpError> {
+        self.enqueue_advance(PpQueueElem {
+            size: Size::ZERO,
+            token,
+            length: 0,
+        })
+    }
+
+    // Both print and mark tags, or neither.
+    pub fn set_tags(&mut self, b: bool) {
+        self.print_tags = b;
+        self.mark_tags = b;
+    }
+
+    pub fn set_print_tags(&mut self, b: bool) {
+        self.print_tags = b;
+    }
+
+    pub fn set_mark_tags(&mut self, b: bool) {
+        self.mark_tags = b;
+    }
+
+    pub fn get_print_tags(&self) -> bool {
+        self.print_tags
+    }
+
+    pub fn get_mark_tags(&self) -> bool {
+        self.mark_tags
+    }
+
+    // Replace the tag-handling functions.
+    pub fn set_tag_functions<T: TagFunctions + 'a>(&mut self, functions: T) {
+        self.tag_functions = Box::new(functions);
+    }
+
+    // Style tagged material with ANSI escape sequences: use the styler as
+    // tag-handling functions and switch tag marking on.
+    pub fn set_ansi_styler(&mut self, styler: AnsiStyler) {
+        self.set_tag_functions(styler);
+        self.mark_tags = true;
     }
 
     // Procedures to format values and use boxes.
     // Should be either in a separate struct of plain functions.
 
-    fn print_as_size(&mut self, s: String, size: usize) {
+    fn print_as_size(&mut self, size: Size, s: Cow<'a, str>) -> Result<(), PpError> {
         if self.curr_depth < self.max_boxes {
-            self.enqueue_string_as(s, size as i32);
+            self.enqueue_string_as(s, i32::from(&size))
+        } else {
+            Ok(())
         }
     }
 
-    fn print_as(&mut self, s: String, size: usize) {
-        self.print_as_size(s, size)
+    // Print a string as if it were `size` columns wide, whatever its content:
+    // for instance an escape sequence or an HTML entity drawn as one glyph.
+    // Sizes are limited so that the queue totals stay below the infinite
+    // size of the engine.
+    pub fn print_as(&mut self, size: usize, s: &str) {
+        self.print_limited(size, Cow::Owned(s.to_string()))
+    }
+
+    fn print_limited(&mut self, size: usize, s: Cow<'a, str>) {
+        let room = (INFINITY - 1 - self.right_total).max(0);
+        let size = min(pp_limit(size), room);
+        let _ = self.print_as_size(Size(size), s);
+    }
+
+    // As print_as, but failing instead of limiting the size.
+    pub fn try_print_as(&mut self, size: usize, s: &str) -> Result<(), PpError> {
+        let size = pp_checked(size)?;
+        self.print_as_size(Size(size), Cow::Owned(s.to_string()))
     }
 
     pub fn print_string(&mut self, s: &str) {
-        self.print_as(s.to_string(), s.len());
+        self.print_as((self.width)(s), s);
+    }
+
+    pub fn try_print_string(&mut self, s: &str) -> Result<(), PpError> {
+        self.try_print_as((self.width)(s), s)
+    }
+
+    // Print a string without copying it: a string living as long as the
+    // printer, such as a literal, is borrowed, and a String is moved into
+    // the queue.
+    pub fn print_str<S: Into<Cow<'a, str>>>(&mut self, s: S) {
+        let s = s.into();
+        self.print_limited((self.width)(&s), s)
+    }
+
+    pub fn try_print_str<S: Into<Cow<'a, str>>>(&mut self, s: S) -> Result<(), PpError> {
+        let s = s.into();
+        let size = pp_checked((self.width)(&s))?;
+        self.print_as_size(Size(size), s)
     }
 
     // TODO@@ implement print_int etc if needed
 
     pub fn open_hbox(&mut self) {
-        self.open_box_gen(0, PpBox::Hbox())
+        let _ = self.open_box_gen(0, PpBox::Hbox());
     }
 
     pub fn open_vbox(&mut self, indent: usize) {
-        self.open_box_gen(indent as i32, PpBox::Vbox())
+        let _ = self.open_box_gen(pp_limit(indent), PpBox::Vbox());
     }
 
     pub fn open_hvbox(&mut self, indent: usize) {
-        self.open_box_gen(indent as i32, PpBox::Hvbox())
+        let _ = self.open_box_gen(pp_limit(indent), PpBox::Hvbox());
     }
 
     pub fn open_hovbox(&mut self, indent: usize) {
-        self.open_box_gen(indent as i32, PpBox::Hovbox())
+        let _ = self.open_box_gen(pp_limit(indent), PpBox::Hovbox());
     }
 
     pub fn open_box(&mut self, indent: usize) {
-        self.open_box_gen(indent as i32, PpBox::Box())
+        let _ = self.open_box_gen(pp_limit(indent), PpBox::Box());
+    }
+
+    pub fn try_open_vbox(&mut self, indent: usize) -> Result<(), PpError> {
+        self.open_box_gen(pp_checked(indent)?, PpBox::Vbox())
+    }
+
+    pub fn try_open_hvbox(&mut self, indent: usize) -> Result<(), PpError> {
+        self.open_box_gen(pp_checked(indent)?, PpBox::Hvbox())
+    }
+
+    pub fn try_open_hovbox(&mut self, indent: usize) -> Result<(), PpError> {
+        self.open_box_gen(pp_checked(indent)?, PpBox::Hovbox())
+    }
+
+    pub fn try_open_box(&mut self, indent: usize) -> Result<(), PpError> {
+        self.open_box_gen(pp_checked(indent)?, PpBox::Box())
     }
 
     pub fn print_newline(&mut self) {
-        self.flush_queue(true);
-        // TODO@@ implement something here probably
-        // self.out_flush;
+        let _ = self.try_print_newline();
     }
 
     pub fn print_flush(&mut self) {
-        self.flush_queue(false);
-        // TODO@@ implement something here probably
-        // self.out_flush;
-    }
-
-    // TODO@@ implement after adding if_newline token type
-    // pub fn force_newline(&mut self) {
-    //     if self.curr_depth < self.max_boxes {
-    //         let token = PpToken::();
-    //         self.tokens.push(token);
-    //         let token_id = self.tokens.len() - 1;
-    //         self.token_sizes[token_id] = Size::ZERO;
-    //         self.token_lengths[token_id] = 0;
-    //
-    //         self.enqueue_advance(token_id)
-    //     }
-    // }
+        let _ = self.try_print_flush();
+    }
+
+    // As print_newline, reporting a failure of the engine while outputting
+    // the pending material.
+    pub fn try_print_newline(&mut self) -> Result<(), PpError> {
+        let result = self.flush_queue(true);
+        self.output_flush();
+        result
+    }
+
+    pub fn try_print_flush(&mut self) -> Result<(), PpError> {
+        let result = self.flush_queue(false);
+        self.output_flush();
+        result
+    }
+
+    // Force a line break inside the current box, at the indentation of
+    // the box.
+    pub fn force_newline(&mut self) {
+        if self.curr_depth < self.max_boxes {
+            let _ = self.enqueue_zero(PpToken::Newline());
+        }
+    }
+
+    // Print the next token only if the line has just been split.
+    pub fn print_if_newline(&mut self) {
+        if self.curr_depth < self.max_boxes {
+            let _ = self.enqueue_zero(PpToken::IfNewline());
+        }
+    }
 
     pub fn print_custom_break(
         &mut self,
         fits: (String, i32, String),
         breaks: (String, i32, String),
     ) {
-        if self.curr_depth < self.max_boxes {
-            let tok_len = fits.0.len_i32() + fits.1 + fits.2.len_i32();
-            let token = PpToken::Break { fits, breaks };
+        let _ = self.try_print_custom_break(fits, breaks);
+    }
 
-            let token_idx = self.add_token(token);
-            self.token_sizes
-                .insert(token_idx, Size(-1 * self.right_total));
-            self.token_lengths.insert(token_idx, tok_len);
+    pub fn try_print_custom_break(
+        &mut self,
+        fits: (String, i32, String),
+        breaks: (String, i32, String),
+    ) -> Result<(), PpError> {
+        let tok_len = self
+            .width_i32(&fits.0)?
+            .checked_add(fits.1)
+            .and_then(|len| len.checked_add(self.width_i32(&fits.2).ok()?))
+            .ok_or(PpError::Overflow)?;
+        let plain = [&fits.0, &fits.2, &breaks.0, &breaks.2]
+            .iter()
+            .all(|s| s.is_empty());
+        let token = if plain {
+            PpToken::Break(fits.1, breaks.1)
+        } else {
+            PpToken::CustomBreak(Box::new((fits, breaks)))
+        };
+        self.scan_break(token, tok_len)
+    }
 
-            self.scan_push(true, token_idx);
+    // Enqueue a break hint of the given length, its size being unknown
+    // until the next break or the end of the box.
+    fn scan_break(&mut self, token: PpToken<'a>, length: i32) -> Result<(), PpError> {
+        if self.curr_depth < self.max_boxes {
+            let elem = PpQueueElem {
+                size: Size(-self.right_total),
+                token,
+                length,
+            };
+            self.scan_push(true, elem)
+        } else {
+            Ok(())
         }
     }
 
     pub fn print_break(&mut self, width: usize, offset: usize) {
-        self.print_custom_break(
-            ("".to_string(), width as i32, "".to_string()),
-            ("".to_string(), offset as i32, "".to_string()),
-        )
+        let width = pp_limit(width);
+        let _ = self.scan_break(PpToken::Break(width, pp_limit(offset)), width);
+    }
+
+    pub fn try_print_break(&mut self, width: usize, offset: usize) -> Result<(), PpError> {
+        let width = pp_checked(width)?;
+        self.scan_break(PpToken::Break(width, pp_checked(offset)?), width)
     }
 
     pub fn print_space(&mut self) {
@@ -785,23 +1208,67 @@ impl BufPrinter {
         self.print_break(0, 0);
     }
 
-    pub fn set_max_boxes(&mut self, n: usize) {
-        if n > 1 {
-            self.max_boxes = n as i32;
+    /*
+     * Tabulation boxes: the tabulation stops set with set_tab on a line can
+     * be reached on the following lines with print_tab or print_tbreak.
+     * */
+
+    pub fn open_tbox(&mut self) {
+        self.curr_depth += 1;
+        if self.curr_depth < self.max_boxes {
+            let _ = self.enqueue_zero(PpToken::TBegin());
         }
     }
 
-    pub fn set_margin(&mut self, n: usize) {
-        if (n as i32) < INFINITY {
-            self.margin = n as i32;
-        } else {
-            self.margin = INFINITY;
+    pub fn close_tbox(&mut self) {
+        let _ = self.try_close_tbox();
+    }
+
+    pub fn try_close_tbox(&mut self) -> Result<(), PpError> {
+        if self.curr_depth <= 1 {
+            return Err(PpError::NoOpenBox);
+        }
+        let mut result = Ok(());
+        if self.curr_depth < self.max_boxes {
+            result = self.enqueue_zero(PpToken::TEnd());
+        }
+        self.curr_depth -= 1;
+        result
+    }
+
+    // Set a tabulation stop at the current insertion point.
+    pub fn set_tab(&mut self) {
+        if self.curr_depth < self.max_boxes {
+            let _ = self.enqueue_zero(PpToken::SetTab());
+        }
+    }
+
+    // Go to the next tabulation stop, adding `width` spaces. If there is no
+    // stop left on the line, break the line and go to the first stop moved
+    // by `offset`.
+    pub fn print_tbreak(&mut self, width: usize, offset: usize) {
+        let width = pp_limit(width);
+        let _ = self.scan_break(PpToken::TBreak(width, pp_limit(offset)), width);
+    }
+
+    pub fn try_print_tbreak(&mut self, width: usize, offset: usize) -> Result<(), PpError> {
+        let width = pp_checked(width)?;
+        self.scan_break(PpToken::TBreak(width, pp_checked(offset)?), width)
+    }
+
+    pub fn print_tab(&mut self) {
+        self.print_tbreak(0, 0);
+    }
+
+    pub fn set_max_boxes(&mut self, n: usize) {
+        if n > 1 {
+            self.max_boxes = i32::try_from(n).unwrap_or(i32::MAX);
         }
     }
 
     pub fn fprintf<Func, Args>(&mut self, func: Func, args: Args)
     where
-        Func: Fn(&mut BufPrinter, Args),
+        Func: Fn(&mut BufPrinter<'a>, Args),
     {
         func(self, args);
     }
@@ -811,6 +1278,45 @@ impl BufPrinter {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_box_breaks() {
+        let layout = |margin: i32, open: fn(&mut BufPrinter)| {
+            let mut f = BufPrinter::new(margin, 2, margin - 2, 100);
+            open(&mut f);
+            f.print_string("aaaa");
+            f.print_space();
+            f.print_string("bbbb");
+            f.print_break(1, 2);
+            f.print_string("cccc");
+            f.close_box();
+            f.flush_str()
+        };
+
+        // An hbox never breaks, even past the margin.
+        assert_eq!(layout(10, |f| f.open_hbox()), "aaaa bbbb cccc");
+        // A vbox breaks at every hint, even if the material fits.
+        assert_eq!(layout(78, |f| f.open_vbox(0)), "aaaa\nbbbb\n  cccc");
+        // An hvbox stays flat if it fits, and otherwise breaks at every hint.
+        assert_eq!(layout(78, |f| f.open_hvbox(0)), "aaaa bbbb cccc");
+        assert_eq!(layout(10, |f| f.open_hvbox(0)), "aaaa\nbbbb\n  cccc");
+    }
+
+    #[test]
+    fn test_system_box() {
+        // Material outside any box is in the system box, a hovbox, as is the
+        // one reopened after a flush.
+        let mut f = BufPrinter::new(10, 2, 8, 100);
+        for _ in 0..2 {
+            f.print_string("aaaa");
+            f.print_space();
+            f.print_string("bbbb");
+            f.print_space();
+            f.print_string("cccc");
+            f.print_newline();
+        }
+        assert_eq!(f.contents(), "aaaa bbbb\ncccc\naaaa bbbb\ncccc\n");
+    }
+
     #[test]
     fn test_simple() {
         let mut f = BufPrinter::new(78, 10, 68, 10000000);
@@ -833,6 +1339,448 @@ mod tests {
         f.close_box();
         f.print_flush();
 
-        println!("{}", f.out_buf)
+        assert_eq!(
+            f.contents(),
+            "hello world, this is my very long sentence Test sentence 1. Test sentence 2.\n  \
+             Test sentence 3. Test sentence 4. Test sentence 5. Test sentence 6.\n  \
+             Test sentence 7."
+        );
+    }
+
+    #[test]
+    fn test_outputs() {
+        let print = |f: &mut BufPrinter| {
+            f.open_hovbox(2);
+            f.print_string("let x =");
+            f.print_space();
+            f.print_string("1");
+            f.close_box();
+            f.print_newline();
+        };
+
+        let mut f = BufPrinter::new(78, 10, 68, 100);
+        print(&mut f);
+        assert_eq!(f.flush_str(), "let x = 1\n");
+        assert_eq!(f.contents(), "");
+
+        // The tokens reach the writer in one write, on the flush.
+        struct Writes(Vec<u8>, usize);
+        impl std::io::Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1 += 1;
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut writes = Writes(Vec::new(), 0);
+        let mut f = BufPrinter::with_output(78, 10, 68, 100, IoOutput::new(&mut writes));
+        print(&mut f);
+        drop(f);
+        assert_eq!((&writes.0[..], writes.1), (&b"let x = 1\n"[..], 1));
+
+        let mut s = String::new();
+        let mut flushes = 0;
+        let mut f = BufPrinter::with_output(
+            78,
+            10,
+            68,
+            100,
+            FnOutput::new(|t| s.push_str(t), || flushes += 1),
+        );
+        print(&mut f);
+        drop(f);
+        assert_eq!(s, "let x = 1\n");
+        assert_eq!(flushes, 1);
+    }
+
+    #[test]
+    fn test_symbolic_output() {
+        let mut out = SymbolicOutput::new();
+        let mut f = BufPrinter::with_output(12, 4, 8, 100, &mut out);
+        f.open_hvbox(4);
+        f.print_string("let x =");
+        f.print_space();
+        f.print_string("y");
+        f.print_space();
+        f.print_string("+ 1");
+        f.close_box();
+        f.print_flush();
+        drop(f);
+        assert_eq!(
+            out.items(),
+            [
+                OutputItem::String("let x =".to_string()),
+                OutputItem::Newline,
+                OutputItem::Indent(4),
+                OutputItem::String("y".to_string()),
+                OutputItem::Newline,
+                OutputItem::Indent(4),
+                OutputItem::String("+ 1".to_string()),
+                OutputItem::Flush,
+            ]
+        );
+
+        // Indentation rewritten with tabs.
+        let text: String = out
+            .take_items()
+            .into_iter()
+            .map(|item| match item {
+                OutputItem::String(s) => s,
+                OutputItem::Newline => "\n".to_string(),
+                OutputItem::Spaces(n) => " ".repeat(n),
+                OutputItem::Indent(n) => "\t".repeat(n / 4),
+                OutputItem::Flush => String::new(),
+            })
+            .collect();
+        assert_eq!(text, "let x =\n\ty\n\t+ 1");
+        assert!(out.items().is_empty());
+    }
+
+    #[test]
+    fn test_tags_take_no_room() {
+        struct Printed<'v>(&'v mut Vec<String>);
+        impl TagFunctions for Printed<'_> {
+            fn print_open(&mut self, tag: &Stag) {
+                self.0.push(format!("+{}", tag));
+            }
+            fn print_close(&mut self, tag: &Stag) {
+                self.0.push(format!("-{}", tag));
+            }
+        }
+
+        let print = |f: &mut BufPrinter, tags: bool| {
+            f.set_tags(tags);
+            f.open_hovbox(0);
+            for word in ["alpha", "beta", "gamma", "delta"] {
+                f.open_stag(word);
+                f.print_string(word);
+                f.close_stag();
+                f.print_space();
+            }
+            f.close_box();
+            f.print_flush();
+        };
+
+        let mut f = BufPrinter::new(12, 2, 10, 100);
+        print(&mut f, false);
+        let plain = f.flush_str();
+        print(&mut f, true);
+        let marked = f.flush_str();
+        assert_eq!(plain, "alpha beta\ngamma delta ");
+        assert_eq!(
+            marked,
+            "<alpha>alpha</alpha> <beta>beta</beta>\n<gamma>gamma</gamma> <delta>delta</delta> "
+        );
+
+        let mut log = Vec::new();
+        let mut f = BufPrinter::new(12, 2, 10, 100);
+        f.set_tag_functions(Printed(&mut log));
+        f.set_print_tags(true);
+        f.open_stag("outer");
+        f.open_stag("inner");
+        f.print_string("x");
+        f.close_stag();
+        f.print_flush();
+        assert_eq!(f.contents(), "x");
+        drop(f);
+        assert_eq!(log, ["+outer", "+inner", "-inner", "-outer"]);
+    }
+
+    #[test]
+    fn test_ansi_styles() {
+        let mut f = BufPrinter::new(12, 2, 10, 100);
+        f.set_ansi_styler(AnsiStyler::new().with_style("kw", Style::new().bold()));
+        f.open_hovbox(2);
+        f.open_stag(Style::new().fg(Color::Red));
+        f.print_string("error:");
+        f.print_space();
+        f.open_stag("kw");
+        f.print_string("let");
+        f.close_stag();
+        f.print_space();
+        f.print_string("binding");
+        f.close_stag();
+        f.close_box();
+        f.print_flush();
+        assert_eq!(
+            f.contents(),
+            "\x1b[31merror: \x1b[1;31mlet\x1b[0m\x1b[31m\x1b[0m\n  \x1b[31mbinding\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_ansi_flush() {
+        // A style still open at a flush is reset, and not restored after.
+        let mut f = BufPrinter::new(78, 10, 68, 100);
+        f.set_ansi_styler(AnsiStyler::new());
+        f.open_stag(Style::new().fg(Color::Red));
+        f.open_stag(Style::new().bold());
+        f.print_string("red");
+        f.print_newline();
+        f.print_string("plain?");
+        f.print_flush();
+        assert_eq!(
+            f.contents(),
+            "\x1b[31m\x1b[1;31mred\x1b[0m\x1b[31m\x1b[0m\nplain?"
+        );
+    }
+
+    #[test]
+    fn test_pp_printf() {
+        enum Expr {
+            Int(i64),
+            Add(Box<Expr>, Box<Expr>),
+        }
+
+        fn pp_expr(f: &mut BufPrinter, e: &Expr) {
+            match e {
+                Expr::Int(n) => pp_printf!(f, "%d", n),
+                Expr::Add(a, b) => pp_printf!(f, "@[<hov 2>(%a +@ %a)@]", pp_expr, a, pp_expr, b),
+            }
+        }
+
+        let e = Expr::Add(
+            Box::new(Expr::Add(Box::new(Expr::Int(1)), Box::new(Expr::Int(20)))),
+            Box::new(Expr::Int(300)),
+        );
+        let mut f = BufPrinter::new(16, 4, 12, 100);
+        let f = &mut f;
+        pp_printf!(f, "@[<v 2>let %s =@ %a@]@.", "x", pp_expr, &e);
+        pp_printf!(f, "@[<h>%-4s|%04d|%x|%S@]@.", "ab", 7, 255, "q");
+        pp_printf!(f, "@[<hov 2>@<1>%s@ @<1>→x@ y@]@?", "&lambda;");
+        assert_eq!(
+            f.contents(),
+            "let x =\n  ((1 + 20) +\n    300)\nab  |0007|ff|\"q\"\n&lambda; →x y"
+        );
+    }
+
+    #[test]
+    fn test_display_width() {
+        let print = |f: &mut BufPrinter| {
+            f.open_hovbox(0);
+            for word in ["naïve", "café", "日本語", "über"] {
+                f.print_string(word);
+                f.print_space();
+            }
+            f.close_box();
+            f.flush_str()
+        };
+
+        // Counting bytes, the first line would take 22 columns.
+        let mut f = BufPrinter::new(18, 2, 16, 100);
+        assert_eq!(print(&mut f), "naïve café 日本語\nüber ");
+
+        f.set_width_function(|s: &str| s.chars().count() * 2);
+        assert_eq!(print(&mut f), "naïve\ncafé 日本語\nüber ");
+    }
+
+    #[test]
+    fn test_print_as() {
+        let mut f = BufPrinter::new(12, 2, 10, 100);
+        f.open_hovbox(0);
+        for _ in 0..4 {
+            f.print_as(1, "&rarr;");
+            f.print_string("abc");
+            f.print_space();
+        }
+        f.close_box();
+        assert_eq!(f.flush_str(), "&rarr;abc &rarr;abc\n&rarr;abc &rarr;abc ");
+
+        // An oversized text is laid out as very wide, not as infinite.
+        f.open_hvbox(0);
+        f.print_as(usize::MAX, "x");
+        f.print_as(usize::MAX, "x");
+        f.print_space();
+        f.print_string("y");
+        f.close_box();
+        assert_eq!(f.flush_str(), "xx\ny");
+        assert_eq!((f.left_total, f.right_total), (1, 1));
+    }
+
+    #[test]
+    fn test_tabulation_box() {
+        let mut f = BufPrinter::new(40, 4, 36, 100);
+        f.open_tbox();
+        for header in ["name     ", "type    ", "offset"] {
+            f.set_tab();
+            f.print_string(header);
+        }
+        for row in [["main", "fn", "0x1000"], ["counter", "static", "0x2008"]] {
+            for cell in row {
+                f.print_tab();
+                f.print_string(cell);
+            }
+        }
+        f.close_tbox();
+        assert_eq!(
+            f.flush_str(),
+            "name     type    offset\nmain     fn      0x1000\ncounter  static  0x2008"
+        );
+    }
+
+    #[test]
+    fn test_newlines() {
+        let print = |f: &mut BufPrinter, ctors: &[&str]| {
+            f.open_hvbox(2);
+            f.print_string("type t =");
+            for (i, ctor) in ctors.iter().enumerate() {
+                f.print_space();
+                if i == 0 {
+                    f.print_if_newline();
+                }
+                f.print_string("| ");
+                f.print_string(ctor);
+            }
+            f.close_box();
+            f.flush_str()
+        };
+
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        assert_eq!(print(&mut f, &["A", "B"]), "type t = A | B");
+        assert_eq!(
+            print(&mut f, &["Alpha", "Beta", "Gamma"]),
+            "type t =\n  | Alpha\n  | Beta\n  | Gamma"
+        );
+
+        f.open_hovbox(4);
+        f.print_string("x");
+        f.force_newline();
+        f.print_string("y");
+        f.print_space();
+        f.print_string("z");
+        f.close_box();
+        assert_eq!(f.flush_str(), "x\n    y z");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        assert_eq!(f.try_close_box(), Err(PpError::NoOpenBox));
+        assert_eq!(f.try_close_tbox(), Err(PpError::NoOpenBox));
+        f.close_box();
+        assert_eq!(f.try_print_as(usize::MAX, "x"), Err(PpError::Overflow));
+        assert_eq!(f.try_open_hovbox(usize::MAX), Err(PpError::Overflow));
+        assert_eq!(f.try_print_break(1, usize::MAX), Err(PpError::Overflow));
+
+        // The totals overflow, but the plain functions still lay out.
+        let huge = INFINITY as usize - 1;
+        f.try_print_as(huge, "a").unwrap();
+        f.try_print_as(huge, "b").unwrap();
+        assert_eq!(f.try_print_as(huge, "c"), Err(PpError::Overflow));
+        f.print_as(huge, "d");
+        f.print_space();
+        f.print_string("e");
+        assert_eq!(f.try_print_flush(), Ok(()));
+        assert_eq!(f.contents(), "abd\ne");
+
+        f.set_width_function(|_| usize::MAX);
+        assert_eq!(f.try_print_string("wide"), Err(PpError::Overflow));
+        f.print_string("wide");
+        assert_eq!(f.flush_str(), "abd\newide");
+
+        // A token missing from the queue is reported, and the printer is
+        // reinitialised.
+        f.open_hovbox(0);
+        f.scan_stack.push(PpScanT {
+            left_total: INFINITY,
+            queue_idx: usize::MAX,
+            is_break: false,
+        });
+        assert!(matches!(f.try_print_flush(), Err(PpError::Invariant(_))));
+        f.set_width_function(display_width);
+        f.print_string("ok");
+        assert_eq!(f.flush_str(), "ok");
+    }
+
+    #[test]
+    fn test_bounded_queue() {
+        let mut f = BufPrinter::with_output(40, 4, 36, 100, IoOutput::new(std::io::sink()));
+        f.open_hovbox(2);
+        for i in 0..100_000 {
+            f.open_hvbox(1);
+            f.print_string("(");
+            f.print_string(&i.to_string());
+            f.print_cut();
+            f.print_string(")");
+            f.close_box();
+            f.print_space();
+            // Only the lookahead window, about a line wide, is kept.
+            assert!(f.right_total - f.left_total <= 40);
+            assert!(f.queue.len() < 100, "{} tokens queued", f.queue.len());
+            assert!(f.scan_stack.len() < 100);
+        }
+        f.close_box();
+        f.print_flush();
+        // Only the system box of the reinitialised printer is left.
+        assert_eq!(f.queue.len(), 1);
+    }
+
+    // Run a test on a thread with a small stack, as the engine must not
+    // recurse over the queue or the nesting of boxes.
+    fn with_small_stack<F: FnOnce() + Send + 'static>(test: F) {
+        std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_flush_huge_queue() {
+        with_small_stack(|| {
+            let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+            // With an unbounded margin nothing is output before the flush.
+            f.set_margin(usize::MAX);
+            f.open_hovbox(0);
+            for _ in 0..1_000_000 {
+                f.print_string("x");
+                f.print_cut();
+            }
+            assert_eq!(f.queue.len(), 2_000_002);
+            let out = f.flush_str();
+            assert_eq!(out.len(), 1_000_000);
+        });
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        with_small_stack(|| {
+            let depth = 200_000;
+            let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+            for _ in 0..depth {
+                f.open_hvbox(1);
+                f.print_string("(");
+                f.print_cut();
+            }
+            for _ in 0..depth {
+                f.print_string(")");
+                f.close_box();
+            }
+            let out = f.flush_str();
+            assert_eq!(out.matches('(').count(), depth);
+            assert_eq!(out.matches(')').count(), depth);
+            // Lines are never indented past the maximum indentation.
+            assert!(out
+                .lines()
+                .all(|line| line.len() - line.trim_start().len() <= 68));
+        });
+    }
+
+    #[test]
+    fn test_long_doc() {
+        with_small_stack(|| {
+            let doc = Doc::concat((0..1_000_000).map(|_| Doc::text("x")));
+            let soft = (Doc::text(",") + Doc::softline() + doc.clone()).group();
+            let mut f = BufPrinter::new(78, 10, 68, i32::MAX);
+            f.set_margin(usize::MAX);
+            f.print_doc(&doc);
+            f.print_doc(&soft);
+            assert_eq!(f.flush_str().matches('x').count(), 2_000_000);
+            drop(soft);
+            drop(doc);
+        });
     }
 }
@@ -0,0 +1,336 @@
+// Output sinks for the pretty-printer.
+//
+// The engine never writes text itself: every string, newline and run of
+// spaces it decides to emit goes through an `Output`. This is the Rust
+// counterpart of OCaml's `formatter_out_functions` record.
+
+use std::{fmt, io};
+
+// A run of spaces used to write indentation and break spaces in bulk.
+const SPACES: &str = "                                                                ";
+
+/*
+* The output device of a pretty-printer.
+*
+* Only `output_string` is required; the other functions default to writing
+* the corresponding characters through it.
+*/
+pub trait Output {
+    // Write a string.
+    fn output_string(&mut self, s: &str);
+
+    // Write a newline.
+    fn output_newline(&mut self) {
+        self.output_string("\n");
+    }
+
+    // Write `n` spaces for a break hint that fits on the current line.
+    fn output_spaces(&mut self, n: usize) {
+        let mut n = n;
+        while n > 0 {
+            let chunk = n.min(SPACES.len());
+            self.output_string(&SPACES[..chunk]);
+            n -= chunk;
+        }
+    }
+
+    // Write `n` spaces of indentation after a newline.
+    fn output_indent(&mut self, n: usize) {
+        self.output_spaces(n);
+    }
+
+    // Flush the device, called by `print_flush` and `print_newline`.
+    fn flush(&mut self) {}
+
+    // The accumulated text, for devices which keep it in memory.
+    fn contents(&self) -> Option<&str> {
+        None
+    }
+
+    // Take the accumulated text out of the device, leaving it empty.
+    fn take_contents(&mut self) -> Option<String> {
+        None
+    }
+}
+
+impl<T: Output + ?Sized> Output for &mut T {
+    fn output_string(&mut self, s: &str) {
+        (**self).output_string(s)
+    }
+
+    fn output_newline(&mut self) {
+        (**self).output_newline()
+    }
+
+    fn output_spaces(&mut self, n: usize) {
+        (**self).output_spaces(n)
+    }
+
+    fn output_indent(&mut self, n: usize) {
+        (**self).output_indent(n)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+
+    fn contents(&self) -> Option<&str> {
+        (**self).contents()
+    }
+
+    fn take_contents(&mut self) -> Option<String> {
+        (**self).take_contents()
+    }
+}
+
+impl<T: Output + ?Sized> Output for Box<T> {
+    fn output_string(&mut self, s: &str) {
+        (**self).output_string(s)
+    }
+
+    fn output_newline(&mut self) {
+        (**self).output_newline()
+    }
+
+    fn output_spaces(&mut self, n: usize) {
+        (**self).output_spaces(n)
+    }
+
+    fn output_indent(&mut self, n: usize) {
+        (**self).output_indent(n)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+
+    fn contents(&self) -> Option<&str> {
+        (**self).contents()
+    }
+
+    fn take_contents(&mut self) -> Option<String> {
+        (**self).take_contents()
+    }
+}
+
+// An in-memory string buffer, the default output of a `BufPrinter`.
+impl Output for String {
+    fn output_string(&mut self, s: &str) {
+        self.push_str(s);
+    }
+
+    fn output_newline(&mut self) {
+        self.push('\n');
+    }
+
+    fn contents(&self) -> Option<&str> {
+        Some(self)
+    }
+
+    fn take_contents(&mut self) -> Option<String> {
+        Some(std::mem::take(self))
+    }
+}
+
+/*
+* Output to a `std::io::Write`.
+*
+* The tokens are buffered and reach the writer in large writes, when the
+* buffer is full and on `flush`, so files and sockets need not be wrapped in
+* a `BufWriter` first.
+*
+* The engine has no way to report a write failure in the middle of a
+* layout, so the first error is kept and every later write is dropped;
+* check `error` or `into_inner` once printing is done.
+*/
+pub struct IoOutput<W: io::Write> {
+    writer: io::BufWriter<W>,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> IoOutput<W> {
+    pub fn new(writer: W) -> Self {
+        IoOutput {
+            writer: io::BufWriter::new(writer),
+            error: None,
+        }
+    }
+
+    // The first error returned by the writer, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    // Take back the writer once the buffer is written out, or the first
+    // error it returned.
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.error {
+            None => self.writer.into_inner().map_err(|e| e.into_error()),
+            Some(e) => Err(e),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            if let Err(e) = io::Write::write_all(&mut self.writer, bytes) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<W: io::Write> Output for IoOutput<W> {
+    fn output_string(&mut self, s: &str) {
+        self.write(s.as_bytes())
+    }
+
+    fn flush(&mut self) {
+        if self.error.is_none() {
+            if let Err(e) = io::Write::flush(&mut self.writer) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+/*
+* Output to a `std::fmt::Write`, for instance a `fmt::Formatter` when
+* pretty-printing from a `Display` implementation.
+*
+* As for `IoOutput`, writes stop after the first error.
+*/
+pub struct FmtOutput<W: fmt::Write> {
+    writer: W,
+    error: Option<fmt::Error>,
+}
+
+impl<W: fmt::Write> FmtOutput<W> {
+    pub fn new(writer: W) -> Self {
+        FmtOutput {
+            writer,
+            error: None,
+        }
+    }
+
+    // The error returned by the writer, if any.
+    pub fn error(&self) -> Option<fmt::Error> {
+        self.error
+    }
+
+    // Take back the writer, or the error it returned.
+    pub fn into_inner(self) -> Result<W, fmt::Error> {
+        match self.error {
+            None => Ok(self.writer),
+            Some(e) => Err(e),
+        }
+    }
+}
+
+impl<W: fmt::Write> Output for FmtOutput<W> {
+    fn output_string(&mut self, s: &str) {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_str(s) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+/*
+* Output through user closures, as OCaml's `make_formatter out_string
+* out_flush`. Newlines and spaces go through `out_string`.
+*/
+pub struct FnOutput<'a> {
+    out_string: Box<dyn FnMut(&str) + 'a>,
+    out_flush: Box<dyn FnMut() + 'a>,
+}
+
+impl<'a> FnOutput<'a> {
+    pub fn new<S, F>(out_string: S, out_flush: F) -> Self
+    where
+        S: FnMut(&str) + 'a,
+        F: FnMut() + 'a,
+    {
+        FnOutput {
+            out_string: Box::new(out_string),
+            out_flush: Box::new(out_flush),
+        }
+    }
+}
+
+impl Output for FnOutput<'_> {
+    fn output_string(&mut self, s: &str) {
+        (self.out_string)(s)
+    }
+
+    fn flush(&mut self) {
+        (self.out_flush)()
+    }
+}
+
+// A call made by the engine to its output device.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OutputItem {
+    String(String),
+    Newline,
+    Spaces(usize),
+    Indent(usize),
+    Flush,
+}
+
+/*
+* Output recorded as the calls made by the engine, as OCaml's
+* `symbolic_output_buffer`: the layout decisions can be inspected, or the
+* output rewritten, for instance with tabs for indentation, without parsing
+* text. Pass it by reference to keep access to the items.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolicOutput {
+    items: Vec<OutputItem>,
+}
+
+impl SymbolicOutput {
+    pub fn new() -> Self {
+        SymbolicOutput::default()
+    }
+
+    pub fn items(&self) -> &[OutputItem] {
+        &self.items
+    }
+
+    // Take the items out of the buffer, leaving it empty, as OCaml's
+    // `flush_symbolic_output_buffer`.
+    pub fn take_items(&mut self) -> Vec<OutputItem> {
+        std::mem::take(&mut self.items)
+    }
+
+    pub fn push(&mut self, item: OutputItem) {
+        self.items.push(item);
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
+
+impl Output for SymbolicOutput {
+    fn output_string(&mut self, s: &str) {
+        self.items.push(OutputItem::String(s.to_string()));
+    }
+
+    fn output_newline(&mut self) {
+        self.items.push(OutputItem::Newline);
+    }
+
+    fn output_spaces(&mut self, n: usize) {
+        self.items.push(OutputItem::Spaces(n));
+    }
+
+    fn output_indent(&mut self, n: usize) {
+        self.items.push(OutputItem::Indent(n));
+    }
+
+    fn flush(&mut self) {
+        self.items.push(OutputItem::Flush);
+    }
+}
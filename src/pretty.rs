@@ -0,0 +1,311 @@
+// Pretty-printing of values.
+//
+// `Pretty` is the pretty-printing counterpart of `Debug`: values print the
+// way `{:?}` shows them, but inside boxes so that they break to fit the
+// margin. Sequences are compacting boxes with elements separated by `,@ `,
+// so long lists fill the lines, and constructors such as `Some(...)` indent
+// their argument by 2 when it does not fit.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    rc::Rc,
+    sync::Arc,
+};
+
+use crate::{sep_comma, BufPrinter};
+
+// A value which can be pretty-printed.
+pub trait Pretty {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>);
+}
+
+impl BufPrinter<'_> {
+    // Pretty-print a value.
+    pub fn print_pretty<T: Pretty + ?Sized>(&mut self, value: &T) {
+        value.prettify(self)
+    }
+}
+
+// Pretty-print a value to a string with the given margin, the maximum
+// indentation following as with `set_margin`.
+pub fn pretty_to_string<T: Pretty + ?Sized>(value: &T, margin: i32) -> String {
+    let mut ppf = BufPrinter::new(78, 10, 68, i32::MAX);
+    ppf.set_margin(margin.max(0) as usize);
+    value.prettify(&mut ppf);
+    ppf.flush_str()
+}
+
+// Print `open`, the items separated by `,@ ` and `close`, in a compacting
+// box indented past the opening delimiter.
+fn pp_sequence<T, I>(ppf: &mut BufPrinter<'_>, open: &'static str, items: I, close: &'static str)
+where
+    T: Pretty,
+    I: IntoIterator<Item = T>,
+{
+    ppf.open_hovbox(open.len());
+    ppf.print_str(open);
+    ppf.print_seq(sep_comma, items);
+    ppf.print_str(close);
+    ppf.close_box();
+}
+
+// Print the entries of a map as `{k: v, ...}`.
+fn pp_map<'m, K, V, I>(ppf: &mut BufPrinter<'_>, entries: I)
+where
+    K: Pretty + 'm,
+    V: Pretty + 'm,
+    I: IntoIterator<Item = (&'m K, &'m V)>,
+{
+    ppf.open_hovbox(1);
+    ppf.print_str("{");
+    ppf.print_list(sep_comma, entries, |ppf, (k, v)| {
+        ppf.open_hovbox(2);
+        k.prettify(ppf);
+        ppf.print_str(":");
+        ppf.print_space();
+        v.prettify(ppf);
+        ppf.close_box();
+    });
+    ppf.print_str("}");
+    ppf.close_box();
+}
+
+// Print a constructor applied to one argument, as `Some(x)`.
+fn pp_constructor<T: Pretty + ?Sized>(ppf: &mut BufPrinter<'_>, name: &'static str, arg: &T) {
+    ppf.open_hovbox(2);
+    ppf.print_str(name);
+    ppf.print_str("(");
+    ppf.print_cut();
+    arg.prettify(ppf);
+    ppf.print_str(")");
+    ppf.close_box();
+}
+
+// Values printed as their `Debug` representation, in a single token.
+macro_rules! pretty_debug {
+    ($($t:ty),*) => {
+        $(
+            impl Pretty for $t {
+                fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+                    ppf.print_str(format!("{:?}", self));
+                }
+            }
+        )*
+    };
+}
+
+pretty_debug!(
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    f32,
+    f64,
+    bool,
+    char,
+    str,
+    String,
+    ()
+);
+
+impl<T: Pretty> Pretty for Option<T> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        match self {
+            None => ppf.print_str("None"),
+            Some(x) => pp_constructor(ppf, "Some", x),
+        }
+    }
+}
+
+impl<T: Pretty, E: Pretty> Pretty for Result<T, E> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        match self {
+            Ok(x) => pp_constructor(ppf, "Ok", x),
+            Err(e) => pp_constructor(ppf, "Err", e),
+        }
+    }
+}
+
+impl<T: Pretty> Pretty for (T,) {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        ppf.open_hovbox(1);
+        ppf.print_str("(");
+        self.0.prettify(ppf);
+        ppf.print_str(",)");
+        ppf.close_box();
+    }
+}
+
+macro_rules! pretty_tuple {
+    ($($name:ident $idx:tt),*) => {
+        impl<$($name: Pretty),*> Pretty for ($($name,)*) {
+            fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+                pp_sequence(ppf, "(", [$(&self.$idx as &dyn Pretty),*], ")");
+            }
+        }
+    };
+}
+
+pretty_tuple!(A 0, B 1);
+pretty_tuple!(A 0, B 1, C 2);
+pretty_tuple!(A 0, B 1, C 2, D 3);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+pretty_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+impl<T: Pretty> Pretty for [T] {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "[", self, "]")
+    }
+}
+
+impl<T: Pretty, const N: usize> Pretty for [T; N] {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "[", self, "]")
+    }
+}
+
+impl<T: Pretty> Pretty for Vec<T> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "[", self, "]")
+    }
+}
+
+impl<T: Pretty> Pretty for VecDeque<T> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "[", self, "]")
+    }
+}
+
+impl<T: Pretty, S> Pretty for HashSet<T, S> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "{", self, "}")
+    }
+}
+
+impl<T: Pretty> Pretty for BTreeSet<T> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_sequence(ppf, "{", self, "}")
+    }
+}
+
+impl<K: Pretty, V: Pretty, S> Pretty for HashMap<K, V, S> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_map(ppf, self)
+    }
+}
+
+impl<K: Pretty, V: Pretty> Pretty for BTreeMap<K, V> {
+    fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+        pp_map(ppf, self)
+    }
+}
+
+// Pointers print as the value they point to.
+macro_rules! pretty_deref {
+    ($($t:ty),*) => {
+        $(
+            impl<T: Pretty + ?Sized> Pretty for $t {
+                fn prettify(&self, ppf: &mut BufPrinter<'_>) {
+                    (**self).prettify(ppf)
+                }
+            }
+        )*
+    };
+}
+
+pretty_deref!(&T, &mut T, Box<T>, Rc<T>, Arc<T>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_std() {
+        let v: Vec<Option<(i32, &str)>> = vec![Some((1, "one")), None, Some((3, "three"))];
+        assert_eq!(
+            pretty_to_string(&v, 78),
+            r#"[Some((1, "one")), None, Some((3, "three"))]"#
+        );
+        assert_eq!(
+            pretty_to_string(&v, 20),
+            "[Some((1, \"one\")),\n None,\n Some((3, \"three\"))]"
+        );
+
+        let mut m = BTreeMap::new();
+        m.insert("alpha", vec![1.5, 2.0]);
+        m.insert("beta", vec![]);
+        let r: Result<_, ()> = Ok(Box::new(m));
+        assert_eq!(
+            pretty_to_string(&r, 78),
+            r#"Ok({"alpha": [1.5, 2.0], "beta": []})"#
+        );
+        assert_eq!(
+            pretty_to_string(&r, 16),
+            "Ok(\n  {\"alpha\":\n     [1.5, 2.0],\n   \"beta\": []})"
+        );
+    }
+
+    #[test]
+    fn test_derive() {
+        fn pp_hex(ppf: &mut BufPrinter, n: &u32) {
+            ppf.print_str(format!("{:#x}", n));
+        }
+
+        #[derive(crate::Pretty)]
+        struct Unit;
+
+        #[derive(crate::Pretty)]
+        struct Pair<T>(T, #[pretty(skip)] (), T);
+
+        // A type parameter would be unused without variants.
+        #[derive(crate::Pretty)]
+        enum Never<const N: usize> {}
+
+        #[derive(crate::Pretty)]
+        enum Sym {
+            Undefined,
+            Defined {
+                name: String,
+                #[pretty(with = "pp_hex")]
+                offset: u32,
+                #[pretty(rename = "type")]
+                ty: Pair<Unit>,
+                #[pretty(skip)]
+                _cache: Vec<u8>,
+            },
+        }
+
+        let syms = vec![
+            Sym::Undefined,
+            Sym::Defined {
+                name: "main".to_string(),
+                offset: 4096,
+                ty: Pair(Unit, (), Unit),
+                _cache: vec![1, 2, 3],
+            },
+        ];
+        assert_eq!(
+            pretty_to_string(&syms, 78),
+            r#"[Undefined, Defined { name: "main", offset: 0x1000, type: Pair(Unit, Unit) }]"#
+        );
+        assert_eq!(pretty_to_string(&None::<Never<0>>, 78), "None");
+        assert_eq!(
+            pretty_to_string(&syms, 30),
+            "[Undefined,\n Defined {\n   name: \"main\",\n   offset: 0x1000,\n   type: Pair(Unit, Unit),\n }]"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// Semantic tags, as OCaml's `Format.stag`.
+//
+// A tag delimits a part of the output without taking any room in the
+// layout. Tags are handled in two independent ways:
+// - marking: when the tagged material is output, the marker strings returned
+//   by `mark_open` and `mark_close` are written around it (they are not
+//   counted in the line width);
+// - printing: `print_open` and `print_close` are called when the tag is
+//   opened or closed in the input stream, before any layout.
+
+use std::fmt;
+
+use crate::ansi::Style;
+
+// A semantic tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Stag {
+    // A named tag, as OCaml's `String_tag`.
+    String(String),
+    // A text style, see `AnsiStyler`.
+    Style(Style),
+}
+
+impl From<&str> for Stag {
+    fn from(s: &str) -> Self {
+        Stag::String(s.to_string())
+    }
+}
+
+impl From<String> for Stag {
+    fn from(s: String) -> Self {
+        Stag::String(s)
+    }
+}
+
+impl From<Style> for Stag {
+    fn from(style: Style) -> Self {
+        Stag::Style(style)
+    }
+}
+
+impl fmt::Display for Stag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stag::String(s) => f.write_str(s),
+            Stag::Style(style) => write!(f, "{:?}", style),
+        }
+    }
+}
+
+/*
+* The tag-handling functions of a pretty-printer, as OCaml's
+* `formatter_stag_functions`.
+*
+* By default a named tag `t` is marked as `<t>` ... `</t>`, other tags are
+* not marked, and printing does nothing.
+*/
+pub trait TagFunctions {
+    // The marker written when the tagged material starts being output.
+    fn mark_open(&mut self, tag: &Stag) -> String {
+        match tag {
+            Stag::String(s) => format!("<{}>", s),
+            Stag::Style(_) => String::new(),
+        }
+    }
+
+    // The marker written when the tagged material has been output.
+    fn mark_close(&mut self, tag: &Stag) -> String {
+        match tag {
+            Stag::String(s) => format!("</{}>", s),
+            Stag::Style(_) => String::new(),
+        }
+    }
+
+    // The marker written before the newline of a line break occurring while
+    // some tags are marked.
+    fn mark_line_end(&mut self) -> String {
+        String::new()
+    }
+
+    // The marker written after the indentation of such a line break.
+    fn mark_line_start(&mut self) -> String {
+        String::new()
+    }
+
+    // Called when the tag is opened in the input stream.
+    fn print_open(&mut self, _tag: &Stag) {}
+
+    // Called when the tag is closed in the input stream.
+    fn print_close(&mut self, _tag: &Stag) {}
+}
+
+// The default tag-handling functions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultTagFunctions;
+
+impl TagFunctions for DefaultTagFunctions {}
+
+impl<T: TagFunctions + ?Sized> TagFunctions for &mut T {
+    fn mark_open(&mut self, tag: &Stag) -> String {
+        (**self).mark_open(tag)
+    }
+
+    fn mark_close(&mut self, tag: &Stag) -> String {
+        (**self).mark_close(tag)
+    }
+
+    fn mark_line_end(&mut self) -> String {
+        (**self).mark_line_end()
+    }
+
+    fn mark_line_start(&mut self) -> String {
+        (**self).mark_line_start()
+    }
+
+    fn print_open(&mut self, tag: &Stag) {
+        (**self).print_open(tag)
+    }
+
+    fn print_close(&mut self, tag: &Stag) {
+        (**self).print_close(tag)
+    }
+}
+
+impl<T: TagFunctions + ?Sized> TagFunctions for Box<T> {
+    fn mark_open(&mut self, tag: &Stag) -> String {
+        (**self).mark_open(tag)
+    }
+
+    fn mark_close(&mut self, tag: &Stag) -> String {
+        (**self).mark_close(tag)
+    }
+
+    fn mark_line_end(&mut self) -> String {
+        (**self).mark_line_end()
+    }
+
+    fn mark_line_start(&mut self) -> String {
+        (**self).mark_line_start()
+    }
+
+    fn print_open(&mut self, tag: &Stag) {
+        (**self).print_open(tag)
+    }
+
+    fn print_close(&mut self, tag: &Stag) {
+        (**self).print_close(tag)
+    }
+}
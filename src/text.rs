@@ -0,0 +1,214 @@
+// Printing of prose, as OCaml's `pp_print_text`, and of justified
+// paragraphs.
+//
+// With `print_text`, the words of the text are printed as they are, each
+// space as a `print_space` break hint and each newline as a forced newline,
+// so that the text wraps to fit the margin within the current box. A
+// `hovbox` fills the lines with the words as a paragraph.
+//
+// A break hint only knows whether it splits the line, not what the line
+// holds, so aligning lines needs more: `print_paragraph` enqueues the words
+// as a single token, which fills the lines when it is formatted, knowing
+// the space left on each of them, and aligns every line between the
+// indentation of the current box and the margin. Its size is the width of
+// the words on one line, so the breaks around it are decided as for a text
+// of that width; aligned lines reach the margin, which is best left to a
+// paragraph of its own, for instance between newlines in a `vbox`.
+
+use crate::{BufPrinter, PpBox, PpFormatT, PpQueueElem, PpToken, Size, INFINITY};
+
+// How the lines of a paragraph are aligned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Justify {
+    // Ragged right: the words are separated by one space.
+    Left,
+    // Flush with both the indentation and the margin, by widening the
+    // spaces, except on the last line and lines of a single word.
+    Full,
+    Center,
+    // Ragged left: flush with the margin.
+    Right,
+}
+
+// The lines of a paragraph, as separated by the newlines of the text, each
+// a list of words with their widths.
+#[derive(Clone, Debug)]
+pub(crate) struct Paragraph {
+    justify: Justify,
+    lines: Vec<Vec<(String, i32)>>,
+}
+
+impl BufPrinter<'_> {
+    pub fn print_text(&mut self, s: &str) {
+        let mut left = 0;
+        for (i, c) in s.match_indices([' ', '\n']) {
+            if left < i {
+                self.print_string(&s[left..i]);
+            }
+            if c == "\n" {
+                self.force_newline();
+            } else {
+                self.print_space();
+            }
+            left = i + 1;
+        }
+        if left < s.len() {
+            self.print_string(&s[left..]);
+        }
+    }
+
+    // Print a paragraph, filling the lines with its words and aligning them
+    // as `justify` says. Runs of whitespace separate the words and newlines
+    // end the lines, whose last line is then never widened.
+    pub fn print_paragraph(&mut self, s: &str, justify: Justify) {
+        if self.curr_depth >= self.max_boxes {
+            return;
+        }
+        let lines: Vec<Vec<(String, i32)>> = s
+            .split('\n')
+            .map(|line| {
+                line.split_whitespace()
+                    .map(|word| (word.to_string(), self.width_limited(word)))
+                    .collect()
+            })
+            .collect();
+        // The width of the words on one line, limited as in print_as.
+        let words = lines.iter().flatten();
+        let size = words.fold(-1, |size: i32, (_, width)| size.saturating_add(width + 1));
+        let room = (INFINITY - 1 - self.right_total).max(0);
+        let size = size.clamp(0, room);
+        let _ = self.enqueue_advance(PpQueueElem {
+            size: Size(size),
+            token: PpToken::Paragraph(Box::new(Paragraph { justify, lines })),
+            length: size,
+        });
+    }
+
+    pub(crate) fn format_paragraph(&mut self, paragraph: &Paragraph) {
+        // In a horizontal box, the words stay on one line.
+        let hbox = matches!(
+            self.format_stack.last(),
+            Some(PpFormatT {
+                box_type: PpBox::Hbox(),
+                ..
+            })
+        );
+        for (i, line) in paragraph.lines.iter().enumerate() {
+            if i > 0 {
+                self.paragraph_newline();
+            }
+            let mut words = &line[..];
+            while !words.is_empty() {
+                // As many words as fit, and at least one.
+                let mut n = 1;
+                let mut width = words[0].1;
+                while n < words.len() {
+                    let next = width.saturating_add(words[n].1 + 1);
+                    if !hbox && next > self.space_left {
+                        break;
+                    }
+                    width = next;
+                    n += 1;
+                }
+                let last = n == words.len();
+                let extra = if hbox {
+                    0
+                } else {
+                    (self.space_left - width).max(0)
+                };
+                let (lead, widen) = match paragraph.justify {
+                    Justify::Left => (0, 0),
+                    Justify::Full if last || n == 1 => (0, 0),
+                    Justify::Full => (0, extra),
+                    Justify::Center => (extra / 2, 0),
+                    Justify::Right => (extra, 0),
+                };
+                if lead > 0 {
+                    self.break_same_line("", lead, "");
+                }
+                // The first gaps take the remainder of the extra spaces.
+                let gaps = (n as i32 - 1).max(1);
+                for (j, (word, width)) in words[..n].iter().enumerate() {
+                    if j > 0 {
+                        let j = j as i32 - 1;
+                        let gap = 1 + widen / gaps + i32::from(j < widen % gaps);
+                        self.break_same_line("", gap, "");
+                    }
+                    self.format_pp_text(word, *width);
+                }
+                words = &words[n..];
+                if !words.is_empty() {
+                    self.paragraph_newline();
+                }
+            }
+        }
+    }
+
+    // Go to a new line at the indentation of the current box.
+    fn paragraph_newline(&mut self) {
+        match self.format_stack.last() {
+            None => self.output_newline(),
+            Some(f) => self.break_line(f.box_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Justify;
+    use crate::BufPrinter;
+
+    #[test]
+    fn test_print_text() {
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        f.open_hovbox(2);
+        f.print_string("error:");
+        f.print_space();
+        f.print_text("the quick brown fox jumps over\nthe lazy  dog");
+        f.close_box();
+        assert_eq!(
+            f.flush_str(),
+            "error: the quick\n  brown fox jumps\n  over\n  the lazy  dog"
+        );
+    }
+
+    #[test]
+    fn test_print_paragraph() {
+        let text = "the quick brown fox jumps over the lazy dog\nand runs";
+        let print = |justify| {
+            let mut f = BufPrinter::new(20, 4, 16, 100);
+            f.open_vbox(2);
+            f.print_string("note:");
+            f.print_cut();
+            f.print_paragraph(text, justify);
+            f.close_box();
+            f.flush_str()
+        };
+        assert_eq!(
+            print(Justify::Left),
+            "note:\n  the quick brown\n  fox jumps over the\n  lazy dog\n  and runs"
+        );
+        assert_eq!(
+            print(Justify::Full),
+            "note:\n  the   quick  brown\n  fox jumps over the\n  lazy dog\n  and runs"
+        );
+        assert_eq!(
+            print(Justify::Center),
+            "note:\n   the quick brown\n  fox jumps over the\n       lazy dog\n       and runs"
+        );
+        assert_eq!(
+            print(Justify::Right),
+            "note:\n     the quick brown\n  fox jumps over the\n            lazy dog\n            and runs"
+        );
+
+        // In a horizontal box, a paragraph is a line of words.
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        f.open_hbox();
+        f.print_paragraph(text, Justify::Full);
+        f.close_box();
+        assert_eq!(
+            f.flush_str(),
+            "the quick brown fox jumps over the lazy dog\nand runs"
+        );
+    }
+}
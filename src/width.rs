@@ -0,0 +1,39 @@
+// Measuring the display width of text.
+//
+// The layout counts columns of a terminal, not bytes: a grapheme cluster
+// (a base character with its combining marks, or an emoji sequence joined
+// by zero-width joiners) takes the width of a single glyph, East Asian wide
+// and fullwidth characters take two columns, and zero-width characters none.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// The number of terminal columns taken by a string.
+pub fn display_width(s: &str) -> usize {
+    if s.is_ascii() {
+        return s.len();
+    }
+    s.graphemes(true).map(grapheme_width).sum()
+}
+
+// A grapheme cluster is drawn as one glyph, at most two columns wide.
+fn grapheme_width(g: &str) -> usize {
+    g.width().min(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("hello"), 5);
+        assert_eq!(display_width("café"), 4);
+        assert_eq!(display_width("cafe\u{301}"), 4);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("ｆｕｌｌ"), 8);
+        assert_eq!(display_width("a\u{200b}b"), 2);
+        assert_eq!(display_width("👩\u{200d}👩\u{200d}👧"), 2);
+        assert_eq!(display_width("🇫🇷"), 2);
+    }
+}
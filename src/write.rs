@@ -0,0 +1,65 @@
+// Writing to a pretty-printer with the `std::fmt` machinery.
+//
+// A `BufPrinter` is a `fmt::Write`, so that `write!(ppf, "{}: {}", k, v)`
+// enqueues the pieces of the formatted text as they are produced, without
+// first building a String. Each piece is printed with `print_string`, so
+// the text is laid out as it is, unless break mode is set: the text is then
+// printed with `print_text`, its spaces becoming break hints.
+
+use std::fmt;
+
+use crate::BufPrinter;
+
+impl BufPrinter<'_> {
+    // Print the text written through `fmt::Write` with `print_text`, turning
+    // its spaces into break hints and its newlines into forced newlines, or
+    // print it as it is.
+    pub fn set_write_breaks(&mut self, b: bool) {
+        self.write_breaks = b;
+    }
+
+    pub fn get_write_breaks(&self) -> bool {
+        self.write_breaks
+    }
+}
+
+impl fmt::Write for BufPrinter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write_breaks {
+            self.print_text(s)
+        } else if !s.is_empty() {
+            self.print_string(s)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use crate::BufPrinter;
+
+    #[test]
+    fn test_write() {
+        let mut f = BufPrinter::new(20, 4, 16, 100);
+        let name = "offset";
+        f.open_hovbox(2);
+        write!(f, "{}: {:>4}", name, 42).unwrap();
+        f.print_space();
+        write!(f, "the quick brown fox jumps").unwrap();
+        f.close_box();
+        assert_eq!(f.flush_str(), "offset:   42\n  the quick brown fox jumps");
+
+        f.set_write_breaks(true);
+        f.open_hovbox(2);
+        write!(f, "{}: {:>4}", name, 42).unwrap();
+        f.print_space();
+        write!(f, "the quick brown fox jumps").unwrap();
+        f.close_box();
+        assert_eq!(
+            f.flush_str(),
+            "offset:   42 the\n  quick brown fox\n  jumps"
+        );
+    }
+}
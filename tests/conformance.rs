@@ -0,0 +1,215 @@
+// Conformance of the layout with OCaml's Format.
+//
+// Each case prints some material on a fresh pretty-printer and compares the
+// output with the file of the same name in `tests/golden`. The cases are
+// written again in OCaml in `tests/golden/gen.ml`, which writes the files
+// from the output of OCaml's Format:
+//
+//     ocaml tests/golden/gen.ml tests/golden
+//
+// A new case goes in both places, and its file comes from running gen.ml,
+// never from the output of this crate. The `golden` job of the CI runs
+// gen.ml and fails if any file differs from OCaml's output. The files were
+// first worked out by hand from format.ml, with no OCaml toolchain at hand,
+// and are only known to be OCaml's output once that job passes.
+
+use std::{fs, path::Path};
+
+use pp::BufPrinter;
+
+// A pretty-printer as OCaml's `formatter_of_buffer`, with the geometry set
+// by `pp_set_geometry ~max_indent ~margin`.
+fn printer(margin: i32, max_indent: i32) -> BufPrinter<'static> {
+    BufPrinter::new(margin, margin - max_indent, max_indent, i32::MAX)
+}
+
+fn words(f: &mut BufPrinter, words: &[&str]) {
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            f.print_space();
+        }
+        f.print_string(word);
+    }
+}
+
+const WORDS: [&str; 8] = [
+    "aaaa", "bbbb", "cccc", "dddd", "eeee", "ffff", "gggg", "hhhh",
+];
+
+// "@[<h>aaaa@ bbbb@ ...@]@?" at margin 20
+fn hbox() -> String {
+    let mut f = printer(20, 16);
+    f.open_hbox();
+    words(&mut f, &WORDS);
+    f.close_box();
+    f.flush_str()
+}
+
+// "@[<v 2>aaaa@ bbbb@,cccc@;<1 4>dddd@]@?"
+fn vbox() -> String {
+    let mut f = printer(78, 68);
+    f.open_vbox(2);
+    f.print_string("aaaa");
+    f.print_space();
+    f.print_string("bbbb");
+    f.print_cut();
+    f.print_string("cccc");
+    f.print_break(1, 4);
+    f.print_string("dddd");
+    f.close_box();
+    f.flush_str()
+}
+
+// "@[<hv 2>aaaa@ bbbb@ cccc@]@?", then eight words, at margin 20
+fn hvbox() -> String {
+    let mut f = printer(20, 16);
+    f.open_hvbox(2);
+    words(&mut f, &WORDS[..3]);
+    f.close_box();
+    f.print_flush();
+    f.open_hvbox(2);
+    words(&mut f, &WORDS);
+    f.close_box();
+    f.flush_str()
+}
+
+// "@[<hov 2>aaaa@ bbbb@ ...@]@?" at margin 20
+fn hovbox() -> String {
+    let mut f = printer(20, 16);
+    f.open_hovbox(2);
+    words(&mut f, &WORDS);
+    f.close_box();
+    f.flush_str()
+}
+
+// "@[<b 0>aa@ @[<b 4>bbbbbbbbbbbbbb@ cccccc@]@ dd@]@." and the same with
+// hov boxes, at margin 20: once a line is indented by an inner box, a box
+// breaks the hints which would bring the next line back to the left, even
+// if the material fits.
+fn boxes() -> String {
+    let mut f = printer(20, 16);
+    for hov in [false, true] {
+        let open = |f: &mut BufPrinter, indent| {
+            if hov {
+                f.open_hovbox(indent)
+            } else {
+                f.open_box(indent)
+            }
+        };
+        open(&mut f, 0);
+        f.print_string("aa");
+        f.print_space();
+        open(&mut f, 4);
+        words(&mut f, &["bbbbbbbbbbbbbb", "cccccc"]);
+        f.close_box();
+        f.print_space();
+        f.print_string("dd");
+        f.close_box();
+        f.print_newline();
+    }
+    f.flush_str()
+}
+
+// "@[<hv 0>[@;<0 2>aaaa;@;<1 2>bbbb%a]@]@?" with
+// `pp_print_custom_break ~fits:("", 0, "") ~breaks:(";", 0, "")` before the
+// closing bracket, at margins 78 and 12.
+fn custom_break() -> String {
+    let print = |margin: i32| {
+        let mut f = printer(margin, margin - 4);
+        f.open_hvbox(0);
+        f.print_string("[");
+        f.print_break(0, 2);
+        f.print_string("aaaa;");
+        f.print_break(1, 2);
+        f.print_string("bbbb");
+        f.print_custom_break(
+            (String::new(), 0, String::new()),
+            (";".to_string(), 0, String::new()),
+        );
+        f.print_string("]");
+        f.close_box();
+        f.flush_str()
+    };
+    print(78) + "\n" + &print(12)
+}
+
+// "@[<hov 1>(a@ @[<hov 1>(b@ @[<hov 1>(c@ @[<hov 1>(d)@])@])@])@]@?" with
+// `pp_set_max_boxes ppf 3`: the boxes past the third one are an ellipsis.
+fn max_boxes() -> String {
+    let mut f = printer(78, 68);
+    f.set_max_boxes(3);
+    for name in ["a", "b", "c", "d"] {
+        f.open_hovbox(1);
+        f.print_string("(");
+        f.print_string(name);
+        if name != "d" {
+            f.print_space();
+        }
+    }
+    for _ in 0..4 {
+        f.print_string(")");
+        f.close_box();
+    }
+    f.flush_str()
+}
+
+// Boxes opened past the maximum indentation are rejected to the left,
+// unless the enclosing box fits: at margin 30 and max_indent 10,
+// "@[<hov 0>xxxxxxxxxxxx@[<hov 2>aaaa@ bbbb@ cccc@ dddd@]@]@?".
+fn max_indent() -> String {
+    let mut f = printer(30, 10);
+    f.open_hovbox(0);
+    f.print_string("xxxxxxxxxxxx");
+    f.open_hovbox(2);
+    words(&mut f, &WORDS[..4]);
+    f.close_box();
+    f.close_box();
+    f.flush_str()
+}
+
+// "@[<hov 2>aaaa@ bbbb@." closes the box, prints a newline and starts
+// again; the material after it is in the system box, a hov box where a
+// trailing space is taken as not fitting: "aaaa@ bbbb@ @?".
+fn print_newline() -> String {
+    let mut f = printer(78, 68);
+    f.open_hovbox(2);
+    words(&mut f, &["aaaa", "bbbb"]);
+    f.print_newline();
+    words(&mut f, &["aaaa", "bbbb"]);
+    f.print_space();
+    f.flush_str()
+}
+
+// A case: the name of its file, and the output of the pretty-printer.
+type Case = (&'static str, fn() -> String);
+
+const CASES: [Case; 9] = [
+    ("hbox", hbox),
+    ("vbox", vbox),
+    ("hvbox", hvbox),
+    ("hovbox", hovbox),
+    ("box", boxes),
+    ("custom_break", custom_break),
+    ("max_boxes", max_boxes),
+    ("max_indent", max_indent),
+    ("print_newline", print_newline),
+];
+
+#[test]
+fn conformance() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut failures = Vec::new();
+    for (name, case) in CASES {
+        let path = dir.join(name).with_extension("txt");
+        let expected =
+            fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let output = case();
+        if output != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{}\n--- output\n{}",
+                name, expected, output
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,78 @@
+(* The expected outputs of tests/conformance.rs, written with OCaml's Format
+   (OCaml 4.08 or later):
+
+     ocaml tests/golden/gen.ml tests/golden
+
+   Each case prints on a fresh formatter with the given geometry, and is
+   flushed at the end, as the Rust cases are. *)
+
+open Format
+
+let run ?(margin = 78) ?(max_indent = 68) print =
+  let b = Buffer.create 64 in
+  let ppf = formatter_of_buffer b in
+  pp_set_geometry ppf ~max_indent ~margin;
+  print ppf;
+  pp_print_flush ppf ();
+  Buffer.contents b
+
+let words ppf l = pp_print_list ~pp_sep:pp_print_space pp_print_string ppf l
+
+let eight = [ "aaaa"; "bbbb"; "cccc"; "dddd"; "eeee"; "ffff"; "gggg"; "hhhh" ]
+
+let custom ppf () =
+  pp_print_custom_break ppf ~fits:("", 0, "") ~breaks:(";", 0, "")
+
+let custom_break margin =
+  run ~margin ~max_indent:(margin - 4) (fun ppf ->
+      fprintf ppf "@[<hv 0>[@;<0 2>aaaa;@;<1 2>bbbb%a]@]" custom ())
+
+let cases =
+  [
+    ( "hbox",
+      fun () ->
+        run ~margin:20 ~max_indent:16 (fun ppf ->
+            fprintf ppf "@[<h>%a@]" words eight) );
+    ( "vbox",
+      fun () -> run (fun ppf -> fprintf ppf "@[<v 2>aaaa@ bbbb@,cccc@;<1 4>dddd@]")
+    );
+    ( "hvbox",
+      fun () ->
+        run ~margin:20 ~max_indent:16 (fun ppf ->
+            fprintf ppf "@[<hv 2>aaaa@ bbbb@ cccc@]@?@[<hv 2>%a@]" words eight) );
+    ( "hovbox",
+      fun () ->
+        run ~margin:20 ~max_indent:16 (fun ppf ->
+            fprintf ppf "@[<hov 2>%a@]" words eight) );
+    ( "box",
+      fun () ->
+        run ~margin:20 ~max_indent:16 (fun ppf ->
+            fprintf ppf "@[<b 0>aa@ @[<b 4>bbbbbbbbbbbbbb@ cccccc@]@ dd@]@.";
+            fprintf ppf
+              "@[<hov 0>aa@ @[<hov 4>bbbbbbbbbbbbbb@ cccccc@]@ dd@]@.") );
+    ("custom_break", fun () -> custom_break 78 ^ "\n" ^ custom_break 12);
+    ( "max_boxes",
+      fun () ->
+        run (fun ppf ->
+            pp_set_max_boxes ppf 3;
+            fprintf ppf
+              "@[<hov 1>(a@ @[<hov 1>(b@ @[<hov 1>(c@ @[<hov 1>(d)@])@])@])@]")
+    );
+    ( "max_indent",
+      fun () ->
+        run ~margin:30 ~max_indent:10 (fun ppf ->
+            fprintf ppf
+              "@[<hov 0>xxxxxxxxxxxx@[<hov 2>aaaa@ bbbb@ cccc@ dddd@]@]") );
+    ( "print_newline",
+      fun () -> run (fun ppf -> fprintf ppf "@[<hov 2>aaaa@ bbbb@.aaaa@ bbbb@ ")
+    );
+  ]
+
+let () =
+  let dir = if Array.length Sys.argv > 1 then Sys.argv.(1) else "." in
+  List.iter
+    (fun (name, case) ->
+      let oc = open_out_bin (Filename.concat dir (name ^ ".txt")) in
+      output_string oc (case ());
+      close_out oc)
+    cases